@@ -0,0 +1,32 @@
+//! Compares [`dot_product`] against a plain scalar loop at OpenAI's embedding
+//! sizes. Run with `--features simd` to measure the SIMD path:
+//!
+//! ```text
+//! cargo bench --bench dot_product --features simd
+//! ```
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use llm_plugin_utils::api::embeddings::dot_product;
+
+fn scalar_dot_product(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn bench_dot_product(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dot_product");
+    for dimensions in [1536, 3072] {
+        let a: Vec<f32> = (0..dimensions).map(|i| (i as f32).sin()).collect();
+        let b: Vec<f32> = (0..dimensions).map(|i| (i as f32 * 0.9).cos()).collect();
+
+        group.bench_with_input(BenchmarkId::new("scalar", dimensions), &(), |bench, _| {
+            bench.iter(|| scalar_dot_product(black_box(&a), black_box(&b)))
+        });
+        group.bench_with_input(BenchmarkId::new("crate", dimensions), &(), |bench, _| {
+            bench.iter(|| dot_product(black_box(&a), black_box(&b)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_dot_product);
+criterion_main!(benches);
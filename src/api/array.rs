@@ -0,0 +1,71 @@
+use ndarray::Array2;
+
+/// An embedding whose length differs from the first one's, found by
+/// [`embeddings_to_array`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RaggedEmbeddings {
+    pub index: usize,
+    pub len: usize,
+    pub expected: usize,
+}
+
+impl std::fmt::Display for RaggedEmbeddings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "embedding {} has {} dimensions, expected {}",
+            self.index, self.len, self.expected
+        )
+    }
+}
+
+impl std::error::Error for RaggedEmbeddings {}
+
+/// Stacks `embeddings` into a matrix with one row per embedding.
+pub fn embeddings_to_array(embeddings: Vec<Vec<f32>>) -> Result<Array2<f32>, RaggedEmbeddings> {
+    let rows = embeddings.len();
+    let expected = embeddings.first().map_or(0, Vec::len);
+    if let Some((index, embedding)) = embeddings
+        .iter()
+        .enumerate()
+        .find(|(_, e)| e.len() != expected)
+    {
+        return Err(RaggedEmbeddings {
+            index,
+            len: embedding.len(),
+            expected,
+        });
+    }
+
+    let data = embeddings.into_iter().flatten().collect();
+    Ok(Array2::from_shape_vec((rows, expected), data).expect("rows have uniform length"))
+}
+
+/// Splits a matrix back into one embedding per row.
+pub fn array_to_embeddings(array: &Array2<f32>) -> Vec<Vec<f32>> {
+    array.rows().into_iter().map(|row| row.to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_array_round_trip() {
+        let embeddings = vec![vec![1., 2., 3.], vec![4., 5., 6.]];
+
+        let array = embeddings_to_array(embeddings.clone()).unwrap();
+        assert_eq!(array.dim(), (2, 3));
+        assert_eq!(array[[1, 0]], 4.);
+        assert_eq!(array_to_embeddings(&array), embeddings);
+
+        assert_eq!(
+            embeddings_to_array(vec![vec![1., 2.], vec![3.]]),
+            Err(RaggedEmbeddings {
+                index: 1,
+                len: 1,
+                expected: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use reqwest::{Client, RequestBuilder};
+
+use super::hooks::Hooks;
+
+/// Where to reach an Azure OpenAI deployment.
+///
+/// Azure routes by deployment rather than by the request's `model`, and
+/// authenticates with an `api-key` header instead of a bearer token.
+#[derive(Debug, Clone)]
+pub struct AzureConfig {
+    /// The resource endpoint, e.g. `https://my-resource.openai.azure.com`.
+    pub endpoint: String,
+    pub deployment: String,
+    /// e.g. `2024-02-01`.
+    pub api_version: String,
+    pub api_key: String,
+    /// Called around each request sent to the deployment.
+    pub hooks: Hooks,
+}
+
+impl AzureConfig {
+    pub fn chat_url(&self) -> String {
+        format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            self.deployment,
+            self.api_version
+        )
+    }
+
+    pub(crate) fn chat_post(&self, client: &Client) -> RequestBuilder {
+        client
+            .post(self.chat_url())
+            .header("Content-Type", "application/json")
+            .header("api-key", &self.api_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_url() {
+        let config = AzureConfig {
+            endpoint: "https://my-resource.openai.azure.com/".into(),
+            deployment: "gpt4".into(),
+            api_version: "2024-02-01".into(),
+            api_key: "key".into(),
+            hooks: Default::default(),
+        };
+
+        assert_eq!(
+            config.chat_url(),
+            "https://my-resource.openai.azure.com/openai/deployments/gpt4/chat/completions?api-version=2024-02-01"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{BoxStream, StreamExt};
+use reqwest::Client;
+
+use super::chat::{ChatRequest, ChatResponse, ChatStream};
+use super::client::{validate_api_key, ApiConfig, KeyError};
+
+pub type ChunkStream =
+    BoxStream<'static, Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>>;
+
+/// Where chat requests are sent.
+///
+/// [`OpenAiBackend`] talks to the live API, while `MockBackend` (behind the `mock`
+/// feature) replays canned responses so code built on the crate can be tested offline.
+pub trait ChatBackend: Send + Sync {
+    fn complete(
+        &self,
+        request: ChatRequest,
+    ) -> BoxFuture<'_, Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>>>;
+
+    fn stream(
+        &self,
+        request: ChatRequest,
+    ) -> Result<ChunkStream, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenAiBackend {
+    config: ApiConfig,
+}
+
+impl OpenAiBackend {
+    pub fn new(client: Client, api_key: impl Into<String>) -> Self {
+        Self::from_config(ApiConfig::new(client, api_key))
+    }
+
+    /// A backend sending requests as `config` says, calling its hooks.
+    pub fn from_config(config: ApiConfig) -> Self {
+        Self { config }
+    }
+
+    /// Like [`new`](Self::new), but checks the key with [`validate_api_key`] first.
+    pub fn validated(client: Client, api_key: impl Into<String>) -> Result<Self, KeyError> {
+        let api_key = api_key.into();
+        validate_api_key(&api_key)?;
+        Ok(Self::new(client, api_key))
+    }
+}
+
+impl ChatBackend for OpenAiBackend {
+    fn complete(
+        &self,
+        request: ChatRequest,
+    ) -> BoxFuture<'_, Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>>> {
+        request.request_config(&self.config).boxed()
+    }
+
+    fn stream(
+        &self,
+        request: ChatRequest,
+    ) -> Result<ChunkStream, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(request.stream_config(&self.config)?.boxed())
+    }
+}
+
+#[cfg(any(test, feature = "mock"))]
+pub use mock::MockBackend;
+
+#[cfg(any(test, feature = "mock"))]
+mod mock {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// A [`ChatBackend`] that answers requests from queues of canned responses,
+    /// in the order they were pushed.
+    #[derive(Debug, Default)]
+    pub struct MockBackend {
+        responses: Mutex<VecDeque<ChatResponse>>,
+        streams: Mutex<VecDeque<Vec<ChatStream>>>,
+    }
+
+    impl MockBackend {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn push_response(&self, response: ChatResponse) -> &Self {
+            self.responses.lock().unwrap().push_back(response);
+            self
+        }
+
+        /// Queues a response whose only message is an assistant reply with `content`.
+        pub fn push_content_response(&self, content: impl Into<String>) -> &Self {
+            self.push_response(ChatResponse::from_content(content))
+        }
+
+        pub fn push_stream(&self, chunks: impl IntoIterator<Item = ChatStream>) -> &Self {
+            self.streams
+                .lock()
+                .unwrap()
+                .push_back(chunks.into_iter().collect());
+            self
+        }
+
+        /// Queues a stream made of one content delta per item of `deltas`.
+        pub fn push_content_stream(
+            &self,
+            deltas: impl IntoIterator<Item = impl Into<String>>,
+        ) -> &Self {
+            self.push_stream(deltas.into_iter().map(ChatStream::from_content))
+        }
+    }
+
+    impl ChatBackend for MockBackend {
+        fn complete(
+            &self,
+            _request: ChatRequest,
+        ) -> BoxFuture<'_, Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>>> {
+            let response = self.responses.lock().unwrap().pop_front();
+            async move { response.ok_or_else(|| "no mock response queued".into()) }.boxed()
+        }
+
+        fn stream(
+            &self,
+            _request: ChatRequest,
+        ) -> Result<ChunkStream, Box<dyn std::error::Error + Send + Sync>> {
+            let chunks = self
+                .streams
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or("no mock stream queued")?;
+            Ok(futures::stream::iter(chunks.into_iter().map(Ok)).boxed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_backend() {
+        let backend = MockBackend::new();
+        backend.push_content_stream(["The answer: {\"a\"", ": 1}", " done"]);
+
+        let response = ChatRequest::builder()
+            .user("Hi")
+            .stream(true)
+            .build()
+            .stream_json_with(&backend)
+            .await
+            .unwrap();
+
+        assert_eq!(response.antecedent, "The answer: ");
+        assert_eq!(response.json.as_deref(), Some("{\"a\": 1}"));
+
+        let error = ChatRequest::builder()
+            .user("Hi")
+            .build()
+            .request_with(&backend)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no mock response queued");
+
+        backend.push_content_response("Hello!");
+        let response = ChatRequest::builder()
+            .user("Hi")
+            .build()
+            .request_with(&backend)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.message().unwrap().content().as_deref(),
+            Some("Hello!")
+        );
+    }
+}
@@ -1,24 +1,121 @@
-use futures::stream::StreamExt;
-use reqwest::Client;
-use reqwest_eventsource::{Event, EventSource};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{Stream, StreamExt, TryStreamExt};
+use reqwest::{header::HeaderMap, Client, RequestBuilder};
 use schemars::{schema::RootSchema, schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
 use serde_aux::field_attributes::deserialize_default_from_empty_object;
 use typed_builder::TypedBuilder;
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::azure::AzureConfig;
+pub use super::backend::{ChatBackend, ChunkStream};
+use super::client::{with_extra_headers, ApiConfig};
+use super::error::{parse_api_error, send_request};
+use super::event_stream::{EventConnection, FrameError};
+use super::hooks::{Call, Endpoint, Hooks, Usage};
+pub use super::parsing::{
+    strip_code_fence, strip_code_fence_tagged, JsonLimitError, JsonLimitKind, JsonLimits,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[allow(non_camel_case_types)]
 pub enum ChatModel {
     #[serde(rename = "gpt-3.5-turbo-0613")]
+    #[serde(alias = "gpt-3.5-turbo")]
     GPT3,
     #[serde(rename = "gpt-3.5-turbo-16k-0613")]
+    #[serde(alias = "gpt-3.5-turbo-16k")]
     GPT3_16K,
     #[serde(rename = "gpt-4")]
     GPT4_MAY,
     #[serde(rename = "gpt-4-0613")]
     GPT4,
     #[serde(rename = "gpt-4-1106-preview")]
+    #[serde(alias = "gpt-4-turbo", alias = "gpt-4-turbo-preview")]
     GPT4_TURBO,
+    #[serde(rename = "o1")]
+    O1,
+    #[serde(rename = "o1-mini")]
+    O1_MINI,
+    #[serde(rename = "o3-mini")]
+    O3_MINI,
+}
+
+impl ChatModel {
+    /// Whether this is one of the o-series reasoning models, which accept
+    /// `reasoning_effort` and reject sampling parameters such as `temperature`.
+    pub fn is_reasoning(&self) -> bool {
+        matches!(self, Self::O1 | Self::O1_MINI | Self::O3_MINI)
+    }
+
+    /// Whether the model takes its output limit as `max_completion_tokens` and
+    /// rejects the older `max_tokens`.
+    pub fn uses_max_completion_tokens(&self) -> bool {
+        self.is_reasoning()
+    }
+
+    /// Whether the model accepts `functions`.
+    pub fn supports_tools(&self) -> bool {
+        !matches!(self, Self::O1_MINI)
+    }
+
+    /// Whether the model accepts image inputs.
+    pub fn supports_vision(&self) -> bool {
+        matches!(self, Self::O1)
+    }
+
+    /// Whether the model accepts `response_format: { "type": "json_object" }`.
+    pub fn supports_json_mode(&self) -> bool {
+        matches!(self, Self::GPT4_TURBO | Self::O1 | Self::O3_MINI)
+    }
+
+    /// The most tokens the model will generate in one response. For the older
+    /// models this is shared with the prompt, as their context window.
+    pub fn max_output_tokens(&self) -> u32 {
+        match self {
+            Self::GPT3 => 4096,
+            Self::GPT3_16K => 16384,
+            Self::GPT4_MAY | Self::GPT4 => 8192,
+            Self::GPT4_TURBO => 4096,
+            Self::O1 | Self::O3_MINI => 100_000,
+            Self::O1_MINI => 65_536,
+        }
+    }
+}
+
+/// How much a reasoning model thinks before answering; see [`ChatModel::is_reasoning`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+/// Output the model is expected to mostly reproduce, e.g. a file being edited,
+/// which lets OpenAI generate the unchanged parts faster.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Prediction {
+    Content { content: String },
+}
+
+impl Prediction {
+    pub fn content(content: impl Into<String>) -> Self {
+        Self::Content {
+            content: content.into(),
+        }
+    }
+}
+
+/// Streaming options; see [`ChatRequest::stream_collect`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamOptions {
+    /// Ask for a final chunk with no choices that carries the token usage.
+    pub include_usage: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,10 +126,31 @@ pub enum FunctionCallType {
     Name(String),
 }
 
-#[derive(Debug, Serialize, Deserialize, TypedBuilder)]
+#[derive(Debug, Clone, Serialize, Deserialize, TypedBuilder)]
 pub struct ChatRequest {
     #[builder(default = ChatModel::GPT4)]
     model: ChatModel,
+    /// Assembled in order by the builder's `messages`, `message`, `user` and `assistant`
+    /// methods. The `system` method instead inserts after any leading system messages,
+    /// so the system prompt comes first regardless of call order. A request without
+    /// messages builds, but fails with [`ChatValidationError::NoMessages`] when sent.
+    #[builder(via_mutators, mutators(
+        pub fn messages(&mut self, messages: impl IntoIterator<Item = ChatMessage>) {
+            self.messages.extend(messages);
+        }
+        pub fn message(&mut self, message: ChatMessage) {
+            self.messages.push(message);
+        }
+        pub fn system(&mut self, content: impl Into<String>) {
+            insert_system_message(&mut self.messages, ChatMessage::new_system(content));
+        }
+        pub fn user(&mut self, content: impl Into<String>) {
+            self.messages.push(ChatMessage::new_user(content, None));
+        }
+        pub fn assistant(&mut self, content: impl Into<String>) {
+            self.messages.push(ChatMessage::new_assistant(content));
+        }
+    ))]
     messages: Vec<ChatMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(default, setter(strip_option))]
@@ -40,12 +158,27 @@ pub struct ChatRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(default, setter(strip_option))]
     function_call: Option<FunctionCallType>,
-    #[builder(default = 0.7, setter(transform = |f: f32| clamp(f, 0., 2.)))]
-    temperature: f32,
+    /// Not sent to reasoning models, which reject it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(
+        default = (!model.is_reasoning()).then_some(DEFAULT_TEMPERATURE),
+        setter(transform = |f: f32| Some(clamp(f, 0., 2.)))
+    )]
+    temperature: Option<f32>,
     #[builder(default = false)]
     stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[builder(default)]
+    #[builder(default, setter(strip_option))]
+    stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(via_mutators, mutators(
+        pub fn stop(&mut self, stop: impl IntoIterator<Item = impl Into<String>>) {
+            self.stop = clamp_stop(stop);
+        }
+        pub fn stop_str(&mut self, stop: impl Into<String>) {
+            self.stop = clamp_stop([stop]);
+        }
+    ))]
     stop: Option<Vec<String>>,
     #[builder(default = 0., setter(transform = |f: f32| clamp(f, -2., 2.)))]
     frequency_penalty: f32,
@@ -55,6 +188,136 @@ pub struct ChatRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(default, setter(strip_option))]
     max_tokens: Option<usize>,
+    /// The newer name for `max_tokens`. Either can be set: whichever the model
+    /// accepts is sent (see [`ChatModel::uses_max_completion_tokens`]), but not both.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(default, setter(strip_option))]
+    max_completion_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(default, setter(strip_option))]
+    logprobs: Option<bool>,
+    /// Number of most likely alternatives to return per token (0-20). Requires `logprobs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(default, setter(strip_option))]
+    top_logprobs: Option<u8>,
+    /// Only accepted by reasoning models.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(default, setter(strip_option))]
+    reasoning_effort: Option<ReasoningEffort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(default, setter(strip_option))]
+    prediction: Option<Prediction>,
+    /// Limits applied to the JSON object extracted by [`ChatRequest::stream_json`].
+    #[serde(skip)]
+    #[builder(default)]
+    json_limits: JsonLimits,
+    /// Skip streamed chunks that aren't valid UTF-8 instead of ending the stream.
+    #[serde(skip)]
+    #[builder(default)]
+    skip_invalid_utf8: bool,
+    /// Called with the text of each SSE comment line (e.g. `: keepalive`) received
+    /// while streaming, which is handy for logging connection health.
+    #[serde(skip)]
+    #[builder(default, setter(transform = |f: impl Fn(&str) + Send + Sync + 'static| Some(CommentHandler(Arc::new(f)))))]
+    on_comment: Option<CommentHandler>,
+    /// Extra headers sent with the request, e.g. for routing through a gateway.
+    /// The built-in `Content-Type` and credential headers take precedence.
+    #[serde(skip)]
+    #[builder(default)]
+    headers: HeaderMap,
+    /// Cache breakpoints by message index, set with [`ChatRequest::with_cache_control`].
+    #[serde(skip)]
+    #[builder(default, setter(skip))]
+    cache_control: BTreeMap<usize, CacheControl>,
+}
+
+/// A callback for the comment lines of a chat stream, set with `ChatRequest::builder().on_comment(...)`.
+#[derive(Clone)]
+pub struct CommentHandler(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl std::fmt::Debug for CommentHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CommentHandler")
+    }
+}
+
+const DEFAULT_TEMPERATURE: f32 = 0.7;
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// OpenAI rejects empty stop sequences and more than four of them, so drop the
+/// empty ones and keep the first four.
+fn clamp_stop(stop: impl IntoIterator<Item = impl Into<String>>) -> Option<Vec<String>> {
+    let mut stop: Vec<String> = stop.into_iter().map(|s| s.into()).collect();
+
+    let len = stop.len();
+    stop.retain(|s| !s.is_empty());
+    if stop.len() < len {
+        log::warn!("dropping {} empty stop sequences", len - stop.len());
+    }
+    if stop.len() > MAX_STOP_SEQUENCES {
+        log::warn!(
+            "OpenAI accepts at most {MAX_STOP_SEQUENCES} stop sequences, dropping {}",
+            stop.len() - MAX_STOP_SEQUENCES
+        );
+        stop.truncate(MAX_STOP_SEQUENCES);
+    }
+
+    (!stop.is_empty()).then_some(stop)
+}
+
+fn insert_system_message(messages: &mut Vec<ChatMessage>, message: ChatMessage) {
+    let index = messages
+        .iter()
+        .take_while(|m| matches!(m, ChatMessage::System { .. }))
+        .count();
+    messages.insert(index, message);
+}
+
+fn write_canonical_json(value: &serde_json::Value, out: &mut String) {
+    use serde_json::Value;
+
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() => {
+                // Request floats are f32, so print them with f32 precision when that's lossless.
+                let f = if f == 0. { 0. } else { f };
+                if f as f32 as f64 == f {
+                    out.push_str(&(f as f32).to_string());
+                } else {
+                    out.push_str(&f.to_string());
+                }
+            }
+            _ => out.push_str(&n.to_string()),
+        },
+        Value::String(s) => out.push_str(&Value::String(s.clone()).to_string()),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(item, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical_json(value, out);
+            }
+            out.push('}');
+        }
+    }
 }
 
 fn clamp<T: core::cmp::PartialOrd>(value: T, min: T, max: T) -> T {
@@ -67,26 +330,54 @@ fn clamp<T: core::cmp::PartialOrd>(value: T, min: T, max: T) -> T {
     value
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatResponse {
     id: String,
     object: String,
     created: u64,
     choices: Vec<ChatChoice>,
+    /// Some proxies leave this out, in which case it's zeroed.
+    #[serde(default)]
     usage: ChatUsage,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(dead_code)]
 pub struct ChatStream {
     id: String,
     object: String,
     created: u64,
     choices: Vec<StreamChoice>,
+    /// Only set on the final, choice-less chunk when `include_usage` is requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    usage: Option<ChatUsage>,
 }
 
 impl ChatResponse {
+    /// A response with a single choice holding `message`, e.g. for a
+    /// [`ChatBackend`] that doesn't call the API.
+    pub fn from_message(message: ChatMessage) -> Self {
+        Self {
+            id: String::new(),
+            object: "chat.completion".into(),
+            created: 0,
+            choices: vec![ChatChoice {
+                index: 0,
+                message,
+                finish_reason: "stop".into(),
+                logprobs: None,
+            }],
+            usage: ChatUsage::default(),
+        }
+    }
+
+    /// A response whose only choice is an assistant message with `content`.
+    pub fn from_content(content: impl Into<String>) -> Self {
+        Self::from_message(ChatMessage::new_assistant(content))
+    }
+
     pub fn message(&self) -> Option<&ChatMessage> {
-        self.choices.get(0).map(|c| &c.message)
+        self.choices.first().map(|c| &c.message)
     }
 
     pub fn function_call(&self) -> Option<&FunctionCall> {
@@ -103,18 +394,138 @@ impl ChatResponse {
         })
     }
 
+    /// The tool calls in the first choice's message, empty if it made none.
+    pub fn tool_calls(&self) -> &[ToolCall] {
+        match self.message() {
+            Some(ChatMessage::Assistant {
+                content: AssistantContent::ToolCalls { tool_calls, .. },
+                ..
+            }) => tool_calls,
+            _ => &[],
+        }
+    }
+
     pub fn messages(&self) -> Vec<&ChatMessage> {
         self.choices.iter().map(|c| &c.message).collect()
     }
 
+    /// Takes the message of every choice without cloning.
+    pub fn into_messages(self) -> Vec<ChatMessage> {
+        self.choices.into_iter().map(|c| c.message).collect()
+    }
+
+    /// Takes the first choice's message without cloning.
+    pub fn into_message(self) -> Option<ChatMessage> {
+        self.choices.into_iter().next().map(|c| c.message)
+    }
+
+    /// The token usage, or all zeroes if the response didn't include it.
     pub fn tokens(&self) -> ChatUsage {
         self.usage
     }
+
+    pub fn audio(&self) -> Option<&AssistantAudio> {
+        self.message().and_then(|m| {
+            if let ChatMessage::Assistant {
+                content: AssistantContent::Audio { audio },
+                ..
+            } = m
+            {
+                Some(audio)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The displayable text of the first choice's message, whether it was sent as
+    /// a string or as parts (in which case non-text parts are skipped).
+    pub fn assistant_text(&self) -> Option<String> {
+        match self.message()? {
+            ChatMessage::Assistant { content, .. } => content.text(),
+            _ => None,
+        }
+    }
+
+    pub fn audio_transcript(&self) -> Option<&str> {
+        self.audio().map(|a| a.transcript.as_str())
+    }
+
+    pub fn logprobs(&self) -> Option<&ChoiceLogprobs> {
+        self.choices.first().and_then(|c| c.logprobs.as_ref())
+    }
+
+    /// The first JSON object embedded in the message, found the same way as when
+    /// streaming with [`ChatRequest::stream_json`], after removing a code fence
+    /// around the whole message (see [`strip_code_fence`]). `None` if there's no
+    /// object or it isn't valid JSON.
+    pub fn extract_json(&self) -> Option<serde_json::Value> {
+        extract_json_from(self.message()?)
+    }
+
+    /// Like [`extract_json`](Self::extract_json), but for every choice, e.g. when
+    /// requesting `n` completions.
+    pub fn extract_json_choices(&self) -> Vec<Option<serde_json::Value>> {
+        self.choices
+            .iter()
+            .map(|c| extract_json_from(&c.message))
+            .collect()
+    }
+
+    /// Deserializes the JSON object embedded in the message, or `Ok(None)` if there
+    /// isn't one.
+    pub fn extract<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> Result<Option<T>, Box<dyn std::error::Error + Send + Sync>> {
+        let json = self
+            .message()
+            .and_then(|m| m.content())
+            .and_then(|content| super::parsing::first_json_object(strip_code_fence(&content)));
+        match json {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn extract_json_from(message: &ChatMessage) -> Option<serde_json::Value> {
+    let json = super::parsing::first_json_object(strip_code_fence(&message.content()?))?;
+    serde_json::from_str(&json).ok()
 }
 
 impl ChatStream {
     pub fn delta(&self) -> Option<ChatDelta> {
-        self.choices.get(0).and_then(|c| c.delta.clone())
+        self.choices.first().and_then(|c| c.delta.clone())
+    }
+
+    /// Every delta in the chunk with the index of the choice it belongs to. When
+    /// requesting `n` completions, chunks for different choices are interleaved.
+    pub fn choice_deltas(&self) -> impl Iterator<Item = (u32, ChatDelta)> + '_ {
+        self.choices
+            .iter()
+            .filter_map(|c| Some((c.index, c.delta.clone()?)))
+    }
+
+    /// The token usage, set on the final chunk when streaming with
+    /// [`StreamOptions::include_usage`].
+    pub fn usage(&self) -> Option<ChatUsage> {
+        self.usage
+    }
+
+    /// A chunk carrying a single content delta.
+    #[cfg(any(test, feature = "mock"))]
+    pub(crate) fn from_content(content: impl Into<String>) -> Self {
+        Self {
+            id: String::new(),
+            object: "chat.completion.chunk".into(),
+            created: 0,
+            choices: vec![StreamChoice {
+                index: 0,
+                delta: Some(ChatDelta::Content(content.into())),
+                finish_reason: None,
+            }],
+            usage: None,
+        }
     }
 }
 
@@ -123,9 +534,34 @@ pub struct ChatChoice {
     index: u32,
     message: ChatMessage,
     finish_reason: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    logprobs: Option<ChoiceLogprobs>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ChoiceLogprobs {
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Option<Vec<u8>>,
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(dead_code)]
 pub struct StreamChoice {
     index: u32,
     #[serde(deserialize_with = "deserialize_default_from_empty_object")]
@@ -133,13 +569,48 @@ pub struct StreamChoice {
     finish_reason: Option<String>,
 }
 
-#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 pub struct ChatUsage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
 }
 
+impl ChatUsage {
+    /// The sum of prompt and completion tokens, which should match `total_tokens`.
+    pub fn total(&self) -> u32 {
+        self.prompt_tokens.saturating_add(self.completion_tokens)
+    }
+}
+
+/// Saturates rather than overflowing, since long-running sessions can accumulate
+/// more tokens than fit in the API's `u32` counts.
+impl std::ops::Add for ChatUsage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            prompt_tokens: self.prompt_tokens.saturating_add(other.prompt_tokens),
+            completion_tokens: self
+                .completion_tokens
+                .saturating_add(other.completion_tokens),
+            total_tokens: self.total_tokens.saturating_add(other.total_tokens),
+        }
+    }
+}
+
+impl std::ops::AddAssign for ChatUsage {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl std::iter::Sum for ChatUsage {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |a, b| a + b)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct FunctionCall {
     name: String,
@@ -151,6 +622,26 @@ impl FunctionCall {
         serde_json::from_str(&self.arguments)
     }
 
+    /// Like [`to_type`](Self::to_type), but the error carries the raw arguments and
+    /// the path to the field that failed, for debugging what the model sent.
+    pub fn to_type_verbose<'a, T: Deserialize<'a>>(&'a self) -> Result<T, FunctionArgsError> {
+        let error = |path: String, source| FunctionArgsError {
+            function: self.name.clone(),
+            arguments: self.arguments.clone(),
+            path,
+            source,
+        };
+
+        let mut deserializer = serde_json::Deserializer::from_str(&self.arguments);
+        let value = serde_path_to_error::deserialize(&mut deserializer)
+            .map_err(|e| error(e.path().to_string(), e.into_inner()))?;
+        // Like `to_type`, reject anything but whitespace after the value.
+        deserializer
+            .end()
+            .map_err(|source| error(".".into(), source))?;
+        Ok(value)
+    }
+
     pub fn name(&self) -> String {
         self.name.clone()
     }
@@ -160,11 +651,226 @@ impl FunctionCall {
     }
 }
 
+/// Returned by [`FunctionCall::to_type_verbose`].
+#[derive(Debug)]
+pub struct FunctionArgsError {
+    pub function: String,
+    /// The arguments as the model sent them.
+    pub arguments: String,
+    /// Where deserialization failed, e.g. `items[2].name`, or `.` for the root.
+    pub path: String,
+    pub source: serde_json::Error,
+}
+
+impl std::fmt::Display for FunctionArgsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid arguments for {} at {}: {} (arguments: {})",
+            self.function, self.path, self.source, self.arguments
+        )
+    }
+}
+
+impl std::error::Error for FunctionArgsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// A call to one of the request's tools. Its [`id`](Self::id) must be echoed in
+/// the [`ChatMessage::new_tool`] reply.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    id: String,
+    /// Only set on calls collected from a stream. Never sent back, since the API
+    /// rejects it in request messages.
+    #[serde(default, skip_serializing)]
+    index: Option<u32>,
+    #[serde(rename = "type", default = "default_tool_type")]
+    tool_type: String,
+    function: FunctionCall,
+}
+
+fn default_tool_type() -> String {
+    "function".into()
+}
+
+impl ToolCall {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The position of the call among the message's tool calls, for calls
+    /// collected from a stream.
+    pub fn index(&self) -> Option<u32> {
+        self.index
+    }
+
+    /// The kind of tool called, currently always `"function"`.
+    pub fn tool_type(&self) -> &str {
+        &self.tool_type
+    }
+
+    pub fn function(&self) -> &FunctionCall {
+        &self.function
+    }
+}
+
+/// Assembles streamed tool call fragments into whole calls, in index order.
+///
+/// Fragments are matched by `index`, since only the first fragment of each call
+/// carries its `id`, type and function name; the arguments are concatenated.
+pub fn collect_tool_calls(deltas: impl IntoIterator<Item = ToolCallDelta>) -> Vec<ToolCall> {
+    let mut calls = std::collections::BTreeMap::<u32, ToolCall>::new();
+    for delta in deltas {
+        let call = calls.entry(delta.index).or_insert_with(|| ToolCall {
+            id: String::new(),
+            index: Some(delta.index),
+            tool_type: default_tool_type(),
+            function: FunctionCall {
+                name: String::new(),
+                arguments: String::new(),
+            },
+        });
+        if let Some(id) = delta.id.filter(|_| call.id.is_empty()) {
+            call.id = id;
+        }
+        if let Some(tool_type) = delta.tool_type {
+            call.tool_type = tool_type;
+        }
+        if let Some(function) = delta.function {
+            if let Some(name) = function.name.filter(|_| call.function.name.is_empty()) {
+                call.function.name = name;
+            }
+            call.function
+                .arguments
+                .push_str(&function.arguments.unwrap_or_default());
+        }
+    }
+    calls.into_values().collect()
+}
+
+#[derive(Debug)]
+pub enum HandlerError {
+    /// The call's arguments didn't deserialize into the handler's input.
+    Arguments(serde_json::Error),
+    /// The handler itself failed.
+    Handler(Box<dyn std::error::Error + Send + Sync>),
+    /// The handler's output couldn't be serialized as the reply.
+    Reply(serde_json::Error),
+}
+
+impl std::fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Arguments(e) => write!(f, "invalid function arguments: {e}"),
+            Self::Handler(e) => write!(f, "function handler failed: {e}"),
+            Self::Reply(e) => write!(f, "error serializing function reply: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+/// Deserializes `call`'s arguments, runs `handler` on them, and wraps its JSON
+/// output in the [`ChatMessage::Function`] reply to send back to the model.
+pub fn handle_function_call<'a, T, R, E>(
+    call: &'a FunctionCall,
+    handler: impl FnOnce(T) -> Result<R, E>,
+) -> Result<ChatMessage, HandlerError>
+where
+    T: Deserialize<'a>,
+    R: Serialize,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let arguments = call.to_type().map_err(HandlerError::Arguments)?;
+    let output = handler(arguments).map_err(|e| HandlerError::Handler(e.into()))?;
+    let reply = serde_json::to_string(&output).map_err(HandlerError::Reply)?;
+    Ok(ChatMessage::new_function(reply, &call.name))
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum AssistantContent {
-    Content { content: String },
-    FunctionCall { function_call: FunctionCall },
+    /// Tool calls, possibly alongside some text. Checked first, since the text
+    /// alone would also match `Content`.
+    ToolCalls {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+        tool_calls: Vec<ToolCall>,
+    },
+    Content {
+        content: String,
+    },
+    FunctionCall {
+        function_call: FunctionCall,
+    },
+    Audio {
+        audio: AssistantAudio,
+    },
+    /// Content sent as a list of parts rather than a single string.
+    Parts {
+        content: Vec<ContentPart>,
+    },
+}
+
+impl AssistantContent {
+    /// The plain content, or the text parts joined together. `None` for function
+    /// calls, audio, tool calls without text, and parts with no text.
+    pub fn text(&self) -> Option<String> {
+        match self {
+            Self::Content { content } => Some(content.clone()),
+            Self::ToolCalls { content, .. } => content.clone(),
+            Self::Parts { content } => {
+                let texts: Vec<_> = content
+                    .iter()
+                    .filter_map(|part| match part {
+                        ContentPart::Text { text } => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect();
+                (!texts.is_empty()).then(|| texts.concat())
+            }
+            Self::FunctionCall { .. } | Self::Audio { .. } => None,
+        }
+    }
+}
+
+/// One part of [`AssistantContent::Parts`]. Part types the crate doesn't know are
+/// kept as `Other`.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text {
+        text: String,
+    },
+    Refusal {
+        refusal: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Audio output from an audio-capable model.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AssistantAudio {
+    pub id: String,
+    /// Base64-encoded audio in the requested format.
+    pub data: String,
+    pub transcript: String,
+    pub expires_at: u64,
+}
+
+/// A prompt-caching hint for providers that need explicit cache breakpoints.
+///
+/// Set per message with [`ChatRequest::with_cache_control`]. OpenAI caches prompt
+/// prefixes automatically, so the hint is never sent to it; see
+/// [`ChatRequest::to_json_with_cache_control`] for backends that want it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CacheControl {
+    Ephemeral,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -189,6 +895,11 @@ pub enum ChatMessage {
         content: String,
         name: String,
     },
+    /// The result of a tool call, identified by the call's [`ToolCall::id`].
+    Tool {
+        content: String,
+        tool_call_id: String,
+    },
 }
 
 impl ChatMessage {
@@ -220,9 +931,66 @@ impl ChatMessage {
             name: name.into(),
         }
     }
+
+    /// The reply to the tool call with id `tool_call_id`.
+    pub fn new_tool(content: impl Into<String>, tool_call_id: impl Into<String>) -> Self {
+        Self::Tool {
+            content: content.into(),
+            tool_call_id: tool_call_id.into(),
+        }
+    }
 }
 
 impl ChatMessage {
+    /// Estimates the tokens this message occupies in a request, including the
+    /// per-message overhead of the chat format.
+    pub fn token_estimate(&self, model: ChatModel) -> usize {
+        use super::tokens::{count_tokens, TOKENS_PER_MESSAGE, TOKENS_PER_NAME};
+
+        let count = |text: &str| count_tokens(text, model);
+        let count_name = |name: &Option<String>| {
+            name.as_deref()
+                .map_or(0, |name| count(name) + TOKENS_PER_NAME)
+        };
+
+        let tokens = match self {
+            Self::User { content, name } => count("user") + count(content) + count_name(name),
+            Self::System { content } => count("system") + count(content),
+            Self::Assistant { content, name } => {
+                let content = match content {
+                    AssistantContent::Content { content } => count(content),
+                    AssistantContent::FunctionCall { function_call } => {
+                        count(&function_call.name) + count(&function_call.arguments)
+                    }
+                    AssistantContent::Audio { audio } => count(&audio.transcript),
+                    AssistantContent::Parts { .. } => count(&content.text().unwrap_or_default()),
+                    AssistantContent::ToolCalls {
+                        content,
+                        tool_calls,
+                    } => {
+                        count(content.as_deref().unwrap_or_default())
+                            + tool_calls
+                                .iter()
+                                .map(|call| {
+                                    count(&call.function.name) + count(&call.function.arguments)
+                                })
+                                .sum::<usize>()
+                    }
+                };
+                count("assistant") + content + count_name(name)
+            }
+            Self::Function { content, name } => {
+                count("function") + count(content) + count(name) + TOKENS_PER_NAME
+            }
+            Self::Tool {
+                content,
+                tool_call_id,
+            } => count("tool") + count(content) + count(tool_call_id),
+        };
+
+        TOKENS_PER_MESSAGE + tokens
+    }
+
     pub fn content(&self) -> Option<String> {
         let content = match self {
             Self::User { content, .. } => content.to_string(),
@@ -232,35 +1000,191 @@ impl ChatMessage {
                 ..
             } => content.to_string(),
             Self::Assistant {
-                content: AssistantContent::FunctionCall { .. },
+                content:
+                    AssistantContent::FunctionCall { .. }
+                    | AssistantContent::Audio { .. }
+                    | AssistantContent::Parts { .. }
+                    | AssistantContent::ToolCalls { .. },
                 ..
             } => return None,
-            Self::Function { content, .. } => content.to_string(),
+            Self::Function { content, .. } | Self::Tool { content, .. } => content.to_string(),
         };
 
         Some(content)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Function {
-    name: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    description: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    parameters: Option<RootSchema>,
+/// Merges adjacent user, system or plain-text assistant messages that share a role
+/// (and name), joining their content with a blank line. Function messages, function
+/// calls and audio are left untouched.
+pub fn normalize_messages(messages: Vec<ChatMessage>) -> Vec<ChatMessage> {
+    normalize_messages_with_separator(messages, "\n\n")
 }
 
-impl Function {
-    pub fn new(name: impl Into<String>, description: Option<String>) -> Self {
-        Self {
-            name: name.into(),
-            description,
-            parameters: None,
-        }
-    }
+/// Like [`normalize_messages`], joining merged content with `separator`.
+pub fn normalize_messages_with_separator(
+    messages: Vec<ChatMessage>,
+    separator: &str,
+) -> Vec<ChatMessage> {
+    let mut normalized: Vec<ChatMessage> = Vec::with_capacity(messages.len());
 
-    pub fn from_object<T: JsonSchema>(
+    for message in messages {
+        let merge_into = match (normalized.last_mut(), &message) {
+            (
+                Some(ChatMessage::User { content, name }),
+                ChatMessage::User {
+                    name: next_name, ..
+                },
+            ) if name == next_name => Some(content),
+            (Some(ChatMessage::System { content }), ChatMessage::System { .. }) => Some(content),
+            (
+                Some(ChatMessage::Assistant {
+                    content: AssistantContent::Content { content },
+                    name,
+                }),
+                ChatMessage::Assistant {
+                    content: AssistantContent::Content { .. },
+                    name: next_name,
+                },
+            ) if name == next_name => Some(content),
+            _ => None,
+        };
+
+        match merge_into {
+            Some(content) => {
+                content.push_str(separator);
+                content.push_str(&message.content().unwrap_or_default());
+            }
+            None => normalized.push(message),
+        }
+    }
+
+    normalized
+}
+
+/// Sends every request concurrently and returns the first successful response,
+/// e.g. to hedge latency by asking two models or regions the same question. The
+/// other requests are cancelled once one succeeds.
+///
+/// Errors are only returned if every request fails, in which case the last one to
+/// fail is returned.
+pub async fn race_requests(
+    requests: Vec<ChatRequest>,
+    client: &Client,
+    api_key: &str,
+) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+    race(
+        requests
+            .into_iter()
+            .map(|request| request.request(client, api_key).boxed()),
+    )
+    .await
+}
+
+/// Like [`race_requests`], but sends the requests to `backend`.
+pub async fn race_requests_with(
+    requests: Vec<ChatRequest>,
+    backend: &dyn ChatBackend,
+) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+    race(
+        requests
+            .into_iter()
+            .map(|request| backend.complete(request)),
+    )
+    .await
+}
+
+async fn race<'a>(
+    requests: impl IntoIterator<
+        Item = BoxFuture<'a, Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>>>,
+    >,
+) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let requests: Vec<_> = requests.into_iter().collect();
+    if requests.is_empty() {
+        return Err("no requests to race".into());
+    }
+    let (response, _) = futures::future::select_ok(requests).await?;
+    Ok(response)
+}
+
+/// A function the model may call.
+///
+/// Either build one with [`Function::builder`], or use the [`Function::new`] and
+/// [`Function::from_object`] shortcuts:
+///
+/// ```
+/// # use llm_plugin_utils::api::chat::Function;
+/// #[derive(schemars::JsonSchema)]
+/// struct Todo {
+///     item: String,
+/// }
+///
+/// Function::builder()
+///     .name("add_todo")
+///     .description("Adds an item to the TODO list")
+///     .parameters_for::<Todo>()
+///     .build();
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, TypedBuilder)]
+pub struct Function {
+    #[builder(setter(into))]
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(default, setter(into, strip_option))]
+    description: Option<String>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_sorted"
+    )]
+    #[builder(
+        via_mutators,
+        mutators(
+            /// Uses a raw schema for the function's parameters.
+            pub fn parameters(&mut self, schema: RootSchema) {
+                self.parameters = Some(schema);
+            }
+            /// Derives the function's parameter schema from `T`.
+            pub fn parameters_for<T: JsonSchema>(&mut self) {
+                self.parameters = Some(schema_for!(T));
+            }
+        )
+    )]
+    parameters: Option<RootSchema>,
+}
+
+/// Serializes `value` with every object's keys in sorted order, whatever map type
+/// schemars or serde_json were built with, so tool definitions serialize identically
+/// from run to run.
+fn serialize_sorted<T: Serialize, S: serde::Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    fn sort(value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(map) => {
+                let sorted: std::collections::BTreeMap<_, _> =
+                    map.into_iter().map(|(k, v)| (k, sort(v))).collect();
+                serde_json::Value::Object(sorted.into_iter().collect())
+            }
+            serde_json::Value::Array(items) => items.into_iter().map(sort).collect(),
+            other => other,
+        }
+    }
+
+    let value = serde_json::to_value(value).map_err(serde::ser::Error::custom)?;
+    sort(value).serialize(serializer)
+}
+
+impl Function {
+    pub fn new(name: impl Into<String>, description: Option<String>) -> Self {
+        Self {
+            name: name.into(),
+            description,
+            parameters: None,
+        }
+    }
+
+    pub fn from_object<T: JsonSchema>(
         name: impl Into<String>,
         description: Option<String>,
     ) -> Self {
@@ -272,18 +1196,112 @@ impl Function {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(rename_all = "snake_case")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "RawDelta", into = "RawDelta")]
 pub enum ChatDelta {
     Role(String),
     Content(String),
-    // None,
+    FunctionCall(FunctionCallDelta),
+    ToolCalls(Vec<ToolCallDelta>),
+}
+
+/// A function call along with the index of the choice that made it.
+pub type ChoiceFunctionCall = (u32, FunctionCall);
+
+/// A fragment of a streamed function call. The name arrives with the first
+/// fragment, and the arguments are spread across the rest.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionCallDelta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+/// A fragment of a streamed tool call; see [`collect_tool_calls`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCallDelta {
+    /// Which of the message's tool calls this fragment belongs to.
+    pub index: u32,
+    /// Only sent with the call's first fragment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub tool_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<FunctionCallDelta>,
+}
+
+/// A delta as sent, which may set several fields at once (e.g. a role with empty
+/// content on the first chunk).
+#[derive(Serialize, Deserialize)]
+struct RawDelta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_call: Option<FunctionCallDelta>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+impl From<ChatDelta> for RawDelta {
+    fn from(delta: ChatDelta) -> Self {
+        let mut raw = Self {
+            role: None,
+            content: None,
+            function_call: None,
+            tool_calls: None,
+        };
+        match delta {
+            ChatDelta::Role(role) => raw.role = Some(role),
+            ChatDelta::Content(content) => raw.content = Some(content),
+            ChatDelta::FunctionCall(function_call) => raw.function_call = Some(function_call),
+            ChatDelta::ToolCalls(tool_calls) => raw.tool_calls = Some(tool_calls),
+        }
+        raw
+    }
+}
+
+impl From<RawDelta> for ChatDelta {
+    fn from(delta: RawDelta) -> Self {
+        match delta {
+            RawDelta {
+                function_call: Some(function_call),
+                ..
+            } => Self::FunctionCall(function_call),
+            RawDelta {
+                tool_calls: Some(tool_calls),
+                ..
+            } => Self::ToolCalls(tool_calls),
+            RawDelta {
+                content: Some(content),
+                ..
+            } if !content.is_empty() => Self::Content(content),
+            RawDelta {
+                role: Some(role), ..
+            } => Self::Role(role),
+            RawDelta { content, .. } => Self::Content(content.unwrap_or_default()),
+        }
+    }
+}
+
+/// One step of a streamed JSON response; see [`ChatRequest::stream_json_deltas`].
+#[derive(Debug, Clone)]
+pub struct JsonDelta {
+    pub prose: String,
+    pub json: Option<String>,
+    /// Character range of `json` within the whole streamed content.
+    pub json_span: Option<Range<usize>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct JsonResponse {
     pub antecedent: String,
     pub json: Option<String>,
+    /// Character range of `json` within the whole streamed content.
+    pub json_span: Option<Range<usize>>,
 }
 
 impl JsonResponse {
@@ -305,24 +1323,394 @@ impl JsonResponse {
             None => Ok(None),
         }
     }
+
+    /// Like [`deserialize`](Self::deserialize), but tolerates the comments and
+    /// trailing commas models often put in their JSON.
+    pub fn deserialize_lenient<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> Result<Option<T>, Box<dyn std::error::Error + Send + Sync>> {
+        match &self.json {
+            Some(json) => {
+                let output: T = serde_json::from_str(&super::parsing::clean_lenient_json(json))?;
+                Ok(Some(output))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the model produced a JSON object.
+    pub fn has_json(&self) -> bool {
+        self.json.is_some()
+    }
+
+    /// Whether the model answered in prose only: no JSON, and not just whitespace.
+    pub fn is_pure_prose(&self) -> bool {
+        self.json.is_none() && !self.antecedent.trim().is_empty()
+    }
+
+    /// Like [`deserialize`](Self::deserialize), but a response without JSON is an
+    /// error instead of `Ok(None)`.
+    pub fn parse_or_err<'de, T: Deserialize<'de>>(
+        &'de self,
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        self.deserialize()?
+            .ok_or_else(|| "the response contained no JSON object".into())
+    }
+}
+
+/// A problem found by [`ChatRequest::validate`] that OpenAI would reject.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatValidationError {
+    NoMessages,
+    TooManyStopSequences(usize),
+    EmptyStopSequence,
+    /// `function_call` names a function that isn't in `functions`.
+    UnknownFunction(String),
+    /// `function_call` is set without any `functions`.
+    FunctionCallWithoutFunctions,
+    /// `top_logprobs` is set without `logprobs`.
+    TopLogprobsWithoutLogprobs,
+    TopLogprobsOutOfRange(u8),
+    /// `reasoning_effort` is set for a model that doesn't reason.
+    ReasoningEffortUnsupported(ChatModel),
+    /// Both `max_tokens` and `max_completion_tokens` are set.
+    BothTokenLimits,
+}
+
+impl std::fmt::Display for ChatValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoMessages => write!(f, "the request has no messages"),
+            Self::TooManyStopSequences(n) => write!(
+                f,
+                "{n} stop sequences given, at most {MAX_STOP_SEQUENCES} are allowed"
+            ),
+            Self::EmptyStopSequence => write!(f, "stop sequences can't be empty"),
+            Self::UnknownFunction(name) => {
+                write!(f, "function_call names {name:?}, which isn't in functions")
+            }
+            Self::FunctionCallWithoutFunctions => {
+                write!(f, "function_call is set but there are no functions")
+            }
+            Self::TopLogprobsWithoutLogprobs => write!(f, "top_logprobs requires logprobs"),
+            Self::TopLogprobsOutOfRange(n) => write!(f, "top_logprobs is {n}, expected 0-20"),
+            Self::ReasoningEffortUnsupported(model) => {
+                write!(f, "{model:?} doesn't accept reasoning_effort")
+            }
+            Self::BothTokenLimits => {
+                write!(
+                    f,
+                    "only one of max_tokens and max_completion_tokens can be set"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChatValidationError {}
+
+/// A request with a single user message and default settings.
+impl From<&str> for ChatRequest {
+    fn from(prompt: &str) -> Self {
+        Self::builder().user(prompt).build()
+    }
+}
+
+/// A request with a single user message and default settings.
+impl From<String> for ChatRequest {
+    fn from(prompt: String) -> Self {
+        Self::builder().user(prompt).build()
+    }
 }
 
 impl ChatRequest {
+    /// Produces a canonical JSON representation of the request, with object keys
+    /// sorted and floats normalized, suitable for hashing as a cache key.
+    pub fn cache_key(&self) -> String {
+        let value = serde_json::to_value(self).expect("error serializing chat request");
+        let mut key = String::new();
+        write_canonical_json(&value, &mut key);
+        key
+    }
+
+    /// Marks the prompt up to and including the message at `index` as cacheable.
+    pub fn with_cache_control(mut self, index: usize, cache: CacheControl) -> Self {
+        self.cache_control.insert(index, cache);
+        self
+    }
+
+    /// The cache breakpoint set on the message at `index`, if any.
+    pub fn cache_control(&self, index: usize) -> Option<CacheControl> {
+        self.cache_control.get(&index).copied()
+    }
+
+    /// Serializes the request with each [`CacheControl`] breakpoint included as a
+    /// `cache_control` field on its message, for backends whose providers need
+    /// explicit breakpoints.
+    pub fn to_json_with_cache_control(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).expect("error serializing chat request");
+        if let Some(messages) = value["messages"].as_array_mut() {
+            for (index, cache_control) in &self.cache_control {
+                if let Some(json) = messages.get_mut(*index) {
+                    json["cache_control"] = serde_json::to_value(cache_control)
+                        .expect("error serializing cache control");
+                }
+            }
+        }
+        value
+    }
+
+    /// Sends the request, failing with an [`ApiError`] carrying OpenAI's error
+    /// details if the response isn't successful.
     pub async fn request(
         self,
         client: &Client,
         api_key: &str,
     ) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
-        let response = client
-            .post("https://api.openai.com/v1/chat/completions")
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", api_key))
+        self.request_config(&ApiConfig::new(client.clone(), api_key))
+            .await
+    }
+
+    /// Like [`request`](Self::request), but sent as `config` says, calling its hooks.
+    pub async fn request_config(
+        self,
+        config: &ApiConfig,
+    ) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+        self.send(config.post("chat/completions"), &config.hooks)
+            .await
+    }
+
+    /// Sends the request and returns the response as-is, regardless of its status.
+    pub async fn request_raw(
+        self,
+        client: &Client,
+        api_key: &str,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        self.request_raw_config(&ApiConfig::new(client.clone(), api_key))
+            .await
+    }
+
+    /// Like [`request_raw`](Self::request_raw), but sent as `config` says, calling
+    /// its hooks. The response body isn't read, so they get no usage.
+    pub async fn request_raw_config(
+        mut self,
+        config: &ApiConfig,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        // With both limits set, send them as they are and let the API reject it.
+        let _ = self.use_model_token_limit();
+        self.drop_unsupported_parameters();
+        let mut call = Call::start(&config.hooks, Endpoint::Chat, self.model);
+        let response = with_extra_headers(config.post("chat/completions"), &self.headers)
             .json(&self)
             .send()
-            .await?
-            .error_for_status()?;
+            .await?;
+        call.status = Some(response.status().as_u16());
+        Ok(response)
+    }
+
+    /// Streams the raw response chunks. `stream` must be set to true.
+    ///
+    /// Fails with an [`ApiError`](super::error::ApiError) if the response isn't successful.
+    pub fn stream(
+        self,
+        client: &Client,
+        api_key: &str,
+    ) -> Result<
+        impl Stream<Item = Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>> + Send,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        self.stream_config(&ApiConfig::new(client.clone(), api_key))
+    }
+
+    /// Like [`stream`](Self::stream), but sent as `config` says, calling its hooks.
+    pub fn stream_config(
+        self,
+        config: &ApiConfig,
+    ) -> Result<
+        impl Stream<Item = Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>> + Send,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        self.stream_from(config.post("chat/completions"), config.hooks.clone())
+    }
+
+    /// Like [`request`](Self::request), but sent to an Azure OpenAI deployment.
+    pub async fn request_azure(
+        self,
+        client: &Client,
+        config: &AzureConfig,
+    ) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+        self.send(config.chat_post(client), &config.hooks).await
+    }
+
+    /// Like [`stream`](Self::stream), but streamed from an Azure OpenAI deployment.
+    pub fn stream_azure(
+        self,
+        client: &Client,
+        config: &AzureConfig,
+    ) -> Result<
+        impl Stream<Item = Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>> + Send,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        self.stream_from(config.chat_post(client), config.hooks.clone())
+    }
+
+    /// Checks for mistakes OpenAI would reject with a 400, returning every one found.
+    pub fn validate(&self) -> Result<(), Vec<ChatValidationError>> {
+        let mut errors = Vec::new();
+
+        if self.messages.is_empty() {
+            errors.push(ChatValidationError::NoMessages);
+        }
+
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_STOP_SEQUENCES {
+                errors.push(ChatValidationError::TooManyStopSequences(stop.len()));
+            }
+            if stop.iter().any(|s| s.is_empty()) {
+                errors.push(ChatValidationError::EmptyStopSequence);
+            }
+        }
+
+        match (&self.function_call, &self.functions) {
+            (Some(FunctionCallType::Name(name)), Some(functions))
+                if !functions.iter().any(|f| f.name == *name) =>
+            {
+                errors.push(ChatValidationError::UnknownFunction(name.clone()));
+            }
+            (Some(FunctionCallType::Name(_)), None) => {
+                errors.push(ChatValidationError::FunctionCallWithoutFunctions)
+            }
+            _ => {}
+        }
+
+        if let Some(top_logprobs) = self.top_logprobs {
+            if self.logprobs != Some(true) {
+                errors.push(ChatValidationError::TopLogprobsWithoutLogprobs);
+            }
+            if top_logprobs > 20 {
+                errors.push(ChatValidationError::TopLogprobsOutOfRange(top_logprobs));
+            }
+        }
+
+        if self.reasoning_effort.is_some() && !self.model.is_reasoning() {
+            errors.push(ChatValidationError::ReasoningEffortUnsupported(self.model));
+        }
+
+        if self.max_tokens.is_some() && self.max_completion_tokens.is_some() {
+            errors.push(ChatValidationError::BothTokenLimits);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Moves the output limit to the field the model accepts, failing if both are set.
+    fn use_model_token_limit(&mut self) -> Result<(), ChatValidationError> {
+        match (self.max_tokens, self.max_completion_tokens) {
+            (Some(_), Some(_)) => return Err(ChatValidationError::BothTokenLimits),
+            (Some(limit), None) if self.model.uses_max_completion_tokens() => {
+                self.max_tokens = None;
+                self.max_completion_tokens = Some(limit);
+            }
+            (None, Some(limit)) if !self.model.uses_max_completion_tokens() => {
+                self.max_tokens = Some(limit);
+                self.max_completion_tokens = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Checks what can't be sent at all and adapts the request to its model.
+    fn prepare(&mut self) -> Result<(), ChatValidationError> {
+        if self.messages.is_empty() {
+            return Err(ChatValidationError::NoMessages);
+        }
+        self.use_model_token_limit()?;
+        self.drop_unsupported_parameters();
+        Ok(())
+    }
+
+    fn drop_unsupported_parameters(&mut self) {
+        if self.model.is_reasoning() && self.temperature.take().is_some() {
+            log::warn!("{:?} doesn't accept temperature, dropping it", self.model);
+        }
+        if !self.model.is_reasoning() && self.reasoning_effort.is_some() {
+            log::warn!("{:?} doesn't accept reasoning_effort", self.model);
+        }
+    }
+
+    async fn send(
+        mut self,
+        request: RequestBuilder,
+        hooks: &Hooks,
+    ) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+        self.prepare()?;
+        let mut call = Call::start(hooks, Endpoint::Chat, self.model);
+        let (response, secrets) =
+            send_request(with_extra_headers(request, &self.headers).json(&self)).await?;
+
+        call.status = Some(response.status().as_u16());
+        if !response.status().is_success() {
+            return Err(parse_api_error(response, &secrets).await.into());
+        }
+
+        let response = response.json::<ChatResponse>().await?;
+        call.usage = Some(Usage::Chat(response.usage));
+        Ok(response)
+    }
+
+    fn stream_from(
+        mut self,
+        request: RequestBuilder,
+        hooks: Hooks,
+    ) -> Result<
+        impl Stream<Item = Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>> + Send,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        if !self.stream {
+            return Err("\"stream\" must be set to true".into());
+        }
+        self.prepare()?;
+
+        let request = with_extra_headers(request, &self.headers).json(&self);
+        let skip_invalid_utf8 = self.skip_invalid_utf8;
+        let on_comment = self.on_comment;
+        let model = self.model;
 
-        Ok(response.json::<ChatResponse>().await?)
+        let open = async move {
+            let mut call = Call::start(&hooks, Endpoint::ChatStream, model);
+            let connection = EventConnection::open(request).await;
+            match &connection {
+                Ok(connection) => call.status = Some(connection.status()),
+                Err(e) => call.record_error(e.as_ref()),
+            }
+            connection
+        };
+        Ok(futures::stream::once(open)
+            .map_ok(move |connection| {
+                chat_chunks(connection, skip_invalid_utf8, on_comment.clone())
+            })
+            .try_flatten())
+    }
+
+    /// Streams the response as it arrives, separating prose from the embedded JSON object.
+    ///
+    /// Each item carries the prose from one content delta; the stream ends with the
+    /// item that completes the JSON object, or when the response ends.
+    pub fn stream_json_deltas(
+        self,
+        client: &Client,
+        api_key: &str,
+    ) -> Result<
+        impl Stream<Item = Result<JsonDelta, Box<dyn std::error::Error + Send + Sync>>> + Send,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        let limits = self.json_limits;
+        Ok(json_deltas(self.stream(client, api_key)?.boxed(), limits))
     }
 
     pub async fn stream_json(
@@ -330,80 +1718,1736 @@ impl ChatRequest {
         client: &Client,
         api_key: &str,
     ) -> Result<JsonResponse, Box<dyn std::error::Error + Send + Sync>> {
+        self.stream_json_bounded(client, api_key, usize::MAX, |_| {})
+            .await
+    }
+
+    /// Like [`stream_json`](Self::stream_json), but deserializes the JSON object into
+    /// `T` as soon as it completes, returning the prose alongside it.
+    pub async fn stream_json_typed<T: serde::de::DeserializeOwned>(
+        self,
+        client: &Client,
+        api_key: &str,
+    ) -> Result<(String, Option<T>), Box<dyn std::error::Error + Send + Sync>> {
+        fold_typed_json(self.stream_json_deltas(client, api_key)?).await
+    }
+
+    /// Like [`stream_json_typed`](Self::stream_json_typed), but streams from `backend`.
+    pub async fn stream_json_typed_with<T: serde::de::DeserializeOwned>(
+        self,
+        backend: &dyn ChatBackend,
+    ) -> Result<(String, Option<T>), Box<dyn std::error::Error + Send + Sync>> {
+        let limits = self.json_limits;
+        fold_typed_json(json_deltas(self.stream_with(backend)?, limits)).await
+    }
+
+    /// Like [`stream_json`](Self::stream_json), but keeps at most `max_prose_bytes`
+    /// of prose in memory.
+    ///
+    /// Whenever the buffered prose grows past the cap it is handed to `flush` and
+    /// cleared, so the returned `antecedent` only holds the prose since the last flush.
+    pub async fn stream_json_bounded(
+        self,
+        client: &Client,
+        api_key: &str,
+        max_prose_bytes: usize,
+        flush: impl FnMut(&str),
+    ) -> Result<JsonResponse, Box<dyn std::error::Error + Send + Sync>> {
+        fold_json_deltas(
+            self.stream_json_deltas(client, api_key)?,
+            max_prose_bytes,
+            flush,
+        )
+        .await
+    }
+
+    /// Like [`stream_json`](Self::stream_json), but also writes every chunk to
+    /// `writer` as it arrives, one JSON object per line (NDJSON), e.g. to keep a
+    /// replayable trace of what the API sent.
+    ///
+    /// Like `stream_json`, this stops reading once the JSON object completes, so
+    /// the trace ends there too.
+    pub async fn stream_tee(
+        self,
+        client: &Client,
+        api_key: &str,
+        writer: impl std::io::Write + Send,
+    ) -> Result<JsonResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let limits = self.json_limits;
+        let chunks = tee_ndjson(self.stream(client, api_key)?.boxed(), writer);
+        fold_json_deltas(json_deltas(chunks, limits), usize::MAX, |_| {}).await
+    }
+
+    /// Like [`stream_tee`](Self::stream_tee), but streams from `backend`.
+    pub async fn stream_tee_with(
+        self,
+        backend: &dyn ChatBackend,
+        writer: impl std::io::Write + Send,
+    ) -> Result<JsonResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let limits = self.json_limits;
+        let chunks = tee_ndjson(self.stream_with(backend)?, writer);
+        fold_json_deltas(json_deltas(chunks, limits), usize::MAX, |_| {}).await
+    }
+
+    /// Streams the response and reassembles it into the [`ChatResponse`] the
+    /// request would have returned without streaming, with a message per choice.
+    ///
+    /// The usage is only known if `stream_options` sets `include_usage`, and is
+    /// zeroed otherwise. `stream` must be set to true.
+    pub async fn stream_collect(
+        self,
+        client: &Client,
+        api_key: &str,
+    ) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+        collect_response(self.stream(client, api_key)?).await
+    }
+
+    /// Like [`stream_collect`](Self::stream_collect), but streams from `backend`.
+    pub async fn stream_collect_with(
+        self,
+        backend: &dyn ChatBackend,
+    ) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+        collect_response(self.stream_with(backend)?).await
+    }
+
+    /// Sends the request through `backend` rather than straight to OpenAI.
+    pub async fn request_with(
+        self,
+        backend: &dyn ChatBackend,
+    ) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+        backend.complete(self).await
+    }
+
+    /// Streams the raw response chunks from `backend`. `stream` must be set to true.
+    pub fn stream_with(
+        self,
+        backend: &dyn ChatBackend,
+    ) -> Result<ChunkStream, Box<dyn std::error::Error + Send + Sync>> {
         if !self.stream {
             return Err("\"stream\" must be set to true".into());
         }
+        backend.stream(self)
+    }
 
-        let client = client
-            .post("https://api.openai.com/v1/chat/completions")
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", api_key))
-            .json(&self);
-
-        let mut state = super::parsing::JsonState::Idle;
-        let mut es = EventSource::new(client)?;
-
-        let mut string_response = String::new();
-        let mut json_response = None;
-
-        while let Some(event) = es.next().await {
-            match event {
-                Ok(Event::Open) => {}
-                Ok(Event::Message(message)) => {
-                    if message.data == "[DONE]" {
-                        es.close();
-                        break;
-                    } else {
-                        let stream: crate::api::chat::ChatStream =
-                            serde_json::from_str(&message.data)?;
-
-                        if let Some(ChatDelta::Content(s)) = stream.delta() {
-                            print!("{s}");
-                            let (new_state, json, filtered) =
-                                super::parsing::parse_json_from_stream(&s, state);
-                            state = new_state;
-                            string_response.push_str(&filtered);
-
-                            if let Some(json) = json {
-                                json_response = Some(json);
-                                es.close();
-                                break;
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    return Err(e.into());
-                }
-                // Err(e) => match e {
-                //     reqwest_eventsource::Error::Utf8(_) => {
-                //         panic!("utf8 error!")
-                //     }
-                //     reqwest_eventsource::Error::InvalidContentType(_) => {
-                //         panic!("invalid content type!")
-                //     }
-                //     reqwest_eventsource::Error::InvalidLastEventId(_) => {
-                //         panic!("invalid last event id!")
-                //     }
-                //     reqwest_eventsource::Error::InvalidStatusCode(_) => {
-                //         panic!("invalid status code!")
-                //     }
-                //     reqwest_eventsource::Error::Parser(_) => {
-                //         panic!("parser error!")
-                //     }
-                //     reqwest_eventsource::Error::StreamEnded => {
-                //         panic!("stream ended!")
-                //     }
-                //     reqwest_eventsource::Error::Transport(_) => {
-                //         panic!("transport error!")
-                //     }
-                // },
-            }
-        }
-
-        Ok(JsonResponse {
-            antecedent: string_response,
-            json: json_response,
-        })
+    /// Like [`stream_json`](Self::stream_json), but streams from `backend`.
+    pub async fn stream_json_with(
+        self,
+        backend: &dyn ChatBackend,
+    ) -> Result<JsonResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let limits = self.json_limits;
+        let chunks = self.stream_with(backend)?;
+        fold_json_deltas(json_deltas(chunks, limits), usize::MAX, |_| {}).await
+    }
+
+    /// Streams a JSON response and deserializes it, re-prompting when it can't be parsed.
+    ///
+    /// After each failed attempt the parse error and the offending output are sent
+    /// back as a user message asking for a correction, up to `max_attempts` requests
+    /// in total. API errors aren't retried.
+    pub async fn request_json_with_repair<T: serde::de::DeserializeOwned>(
+        self,
+        client: &Client,
+        api_key: &str,
+        max_attempts: usize,
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        let backend = super::backend::OpenAiBackend::new(client.clone(), api_key);
+        self.repair_json(&backend, max_attempts).await
+    }
+
+    async fn repair_json<T: serde::de::DeserializeOwned>(
+        mut self,
+        backend: &dyn ChatBackend,
+        max_attempts: usize,
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        self.stream = true;
+
+        let mut attempt = 1;
+        loop {
+            let response = self.clone().stream_json_with(backend).await?;
+            let error = match response.parse_or_err::<T>() {
+                Ok(output) => return Ok(output),
+                Err(e) if attempt >= max_attempts => return Err(e),
+                Err(e) => e,
+            };
+
+            log::warn!("retrying unparseable JSON response (attempt {attempt}): {error}");
+            self.messages.push(ChatMessage::new_user(
+                format!(
+                    "Your previous reply couldn't be parsed: {error}\n\n\
+                     Previous reply:\n{}\n\n\
+                     Reply again with only the corrected JSON object.",
+                    response.to_full_string()
+                ),
+                None,
+            ));
+            attempt += 1;
+        }
+    }
+
+    /// Streams the function calls the model makes, yielding each one as soon as its
+    /// arguments form a complete JSON object rather than when the response ends.
+    /// Only the first choice's calls are yielded; see
+    /// [`stream_choice_function_calls`](Self::stream_choice_function_calls).
+    /// `stream` must be set to true.
+    pub fn stream_function_calls(
+        self,
+        client: &Client,
+        api_key: &str,
+    ) -> Result<
+        impl Stream<Item = Result<FunctionCall, Box<dyn std::error::Error + Send + Sync>>> + Send,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        Ok(first_choice(
+            self.stream_choice_function_calls(client, api_key)?,
+        ))
+    }
+
+    /// Like [`stream_function_calls`](Self::stream_function_calls), but streams from `backend`.
+    pub fn stream_function_calls_with(
+        self,
+        backend: &dyn ChatBackend,
+    ) -> Result<
+        impl Stream<Item = Result<FunctionCall, Box<dyn std::error::Error + Send + Sync>>> + Send,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        Ok(first_choice(
+            self.stream_choice_function_calls_with(backend)?,
+        ))
+    }
+
+    /// Like [`stream_function_calls`](Self::stream_function_calls), but for every
+    /// choice when requesting `n` completions. Each call is yielded with the index
+    /// of its choice, and each choice's arguments are assembled separately.
+    pub fn stream_choice_function_calls(
+        self,
+        client: &Client,
+        api_key: &str,
+    ) -> Result<
+        impl Stream<Item = Result<ChoiceFunctionCall, Box<dyn std::error::Error + Send + Sync>>> + Send,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        let limits = self.json_limits;
+        Ok(function_calls(
+            self.stream(client, api_key)?.boxed(),
+            limits,
+        ))
+    }
+
+    /// Like [`stream_choice_function_calls`](Self::stream_choice_function_calls),
+    /// but streams from `backend`.
+    pub fn stream_choice_function_calls_with(
+        self,
+        backend: &dyn ChatBackend,
+    ) -> Result<
+        impl Stream<Item = Result<ChoiceFunctionCall, Box<dyn std::error::Error + Send + Sync>>> + Send,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        let limits = self.json_limits;
+        Ok(function_calls(self.stream_with(backend)?, limits))
+    }
+
+    /// Streams the whole response, collecting every JSON object in the order it
+    /// was emitted along with the prose between them.
+    ///
+    /// Each object is parsed as soon as it completes, so malformed output fails
+    /// the stream early.
+    pub async fn collect_json_stream(
+        self,
+        client: &Client,
+        api_key: &str,
+    ) -> Result<(Vec<serde_json::Value>, String), Box<dyn std::error::Error + Send + Sync>> {
+        let limits = self.json_limits;
+        collect_json_objects(self.stream(client, api_key)?, limits).await
+    }
+
+    /// Like [`collect_json_stream`](Self::collect_json_stream), but streams from `backend`.
+    pub async fn collect_json_stream_with(
+        self,
+        backend: &dyn ChatBackend,
+    ) -> Result<(Vec<serde_json::Value>, String), Box<dyn std::error::Error + Send + Sync>> {
+        let limits = self.json_limits;
+        collect_json_objects(self.stream_with(backend)?, limits).await
+    }
+}
+
+async fn collect_json_objects(
+    chunks: impl Stream<Item = Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>>,
+    limits: JsonLimits,
+) -> Result<(Vec<serde_json::Value>, String), Box<dyn std::error::Error + Send + Sync>> {
+    let mut parser = super::parsing::JsonStreamParser::with_limits(limits);
+    let mut chunks = std::pin::pin!(chunks);
+
+    let mut objects = Vec::new();
+    let mut prose = String::new();
+
+    while let Some(chunk) = chunks.next().await {
+        if let Some(ChatDelta::Content(s)) = chunk?.delta() {
+            let (completed, filtered) = parser.feed(&s)?;
+            for completed in completed {
+                objects.push(serde_json::from_str(&completed.json)?);
+            }
+            prose.push_str(&filtered);
+        }
+    }
+
+    Ok((objects, prose))
+}
+
+fn json_deltas(
+    chunks: impl Stream<Item = Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>>
+        + Send
+        + Unpin,
+    limits: JsonLimits,
+) -> impl Stream<Item = Result<JsonDelta, Box<dyn std::error::Error + Send + Sync>>> + Send {
+    let parser = super::parsing::JsonStreamParser::with_limits(limits);
+
+    // The last element holds an error to yield after flushing the prose before it.
+    let state = Some((chunks, parser, None));
+    futures::stream::unfold(state, |state| async move {
+        let (mut chunks, mut parser, pending) = state?;
+        if let Some(e) = pending {
+            return Some((Err(e), None));
+        }
+        loop {
+            match chunks.next().await? {
+                Ok(chunk) => {
+                    if let Some(ChatDelta::Content(s)) = chunk.delta() {
+                        let (completed, prose) = match parser.feed(&s) {
+                            Ok(parsed) => parsed,
+                            Err(mut e) if !e.prose.is_empty() => {
+                                let delta = JsonDelta {
+                                    prose: std::mem::take(&mut e.prose),
+                                    json: None,
+                                    json_span: None,
+                                };
+                                return Some((Ok(delta), Some((chunks, parser, Some(e.into())))));
+                            }
+                            Err(e) => return Some((Err(e.into()), None)),
+                        };
+                        let completed = completed.into_iter().next();
+                        let done = completed.is_some();
+                        let (json, json_span) = completed
+                            .map(|c| (Some(c.json), Some(c.span)))
+                            .unwrap_or_default();
+                        let delta = JsonDelta {
+                            prose,
+                            json,
+                            json_span,
+                        };
+                        return Some((Ok(delta), (!done).then_some((chunks, parser, None))));
+                    }
+                }
+                Err(e) => return Some((Err(e), None)),
+            }
+        }
+    })
+}
+
+async fn collect_response(
+    chunks: impl Stream<Item = Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>>,
+) -> Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>> {
+    #[derive(Default)]
+    struct Choice {
+        content: String,
+        function_call: Option<FunctionCall>,
+        tool_calls: Vec<ToolCallDelta>,
+        finish_reason: Option<String>,
+    }
+
+    let mut chunks = std::pin::pin!(chunks);
+    let mut response = ChatResponse {
+        id: String::new(),
+        object: "chat.completion".into(),
+        created: 0,
+        choices: Vec::new(),
+        usage: ChatUsage::default(),
+    };
+    let mut choices = std::collections::BTreeMap::<u32, Choice>::new();
+
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        response.id = chunk.id;
+        response.created = chunk.created;
+        // The usage arrives on a final chunk of its own, with no choices.
+        if let Some(usage) = chunk.usage {
+            response.usage = usage;
+        }
+
+        for stream_choice in chunk.choices {
+            let choice = choices.entry(stream_choice.index).or_default();
+            match stream_choice.delta {
+                Some(ChatDelta::Content(content)) => choice.content.push_str(&content),
+                Some(ChatDelta::FunctionCall(delta)) => {
+                    let call = choice.function_call.get_or_insert_with(|| FunctionCall {
+                        name: String::new(),
+                        arguments: String::new(),
+                    });
+                    if let Some(name) = delta.name {
+                        call.name = name;
+                    }
+                    call.arguments
+                        .push_str(&delta.arguments.unwrap_or_default());
+                }
+                Some(ChatDelta::ToolCalls(deltas)) => choice.tool_calls.extend(deltas),
+                Some(ChatDelta::Role(_)) | None => {}
+            }
+            if stream_choice.finish_reason.is_some() {
+                choice.finish_reason = stream_choice.finish_reason;
+            }
+        }
+    }
+
+    response.choices = choices
+        .into_iter()
+        .map(|(index, choice)| {
+            let content = match choice.function_call {
+                Some(function_call) => AssistantContent::FunctionCall { function_call },
+                None if !choice.tool_calls.is_empty() => AssistantContent::ToolCalls {
+                    content: Some(choice.content).filter(|c| !c.is_empty()),
+                    tool_calls: collect_tool_calls(choice.tool_calls),
+                },
+                None => AssistantContent::Content {
+                    content: choice.content,
+                },
+            };
+            ChatChoice {
+                index,
+                message: ChatMessage::Assistant {
+                    content,
+                    name: None,
+                },
+                finish_reason: choice.finish_reason.unwrap_or_default(),
+                logprobs: None,
+            }
+        })
+        .collect();
+    Ok(response)
+}
+
+/// Writes each chunk to `writer` as a line of JSON before passing it on. A failed
+/// write fails the stream.
+fn tee_ndjson(
+    chunks: ChunkStream,
+    mut writer: impl std::io::Write + Send,
+) -> impl Stream<Item = Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>> + Send + Unpin
+{
+    chunks.map(move |chunk| {
+        let chunk = chunk?;
+        serde_json::to_writer(&mut writer, &chunk)?;
+        writer.write_all(b"\n")?;
+        Ok(chunk)
+    })
+}
+
+/// Assembles function call deltas, using the JSON parser to spot the end of each
+/// call's arguments. Each choice's legacy function call and each of its tool
+/// calls (by `index`) has its own name and parser.
+fn function_calls(
+    chunks: ChunkStream,
+    limits: JsonLimits,
+) -> impl Stream<Item = Result<ChoiceFunctionCall, Box<dyn std::error::Error + Send + Sync>>> + Send
+{
+    // Keyed by choice, then tool call index, with `None` for a `function_call`.
+    let mut calls_in_progress = std::collections::BTreeMap::new();
+
+    chunks
+        .map(move |chunk| {
+            let mut calls = Vec::new();
+            for (index, delta) in chunk?.choice_deltas() {
+                let deltas = match delta {
+                    ChatDelta::FunctionCall(delta) => vec![(None, delta)],
+                    ChatDelta::ToolCalls(deltas) => deltas
+                        .into_iter()
+                        .filter_map(|d| Some((Some(d.index), d.function?)))
+                        .collect(),
+                    _ => continue,
+                };
+                for (tool_index, delta) in deltas {
+                    let (name, parser) = calls_in_progress
+                        .entry((index, tool_index))
+                        .or_insert_with(|| {
+                            (
+                                String::new(),
+                                super::parsing::JsonStreamParser::with_limits(limits),
+                            )
+                        });
+                    if let Some(next) = delta.name {
+                        *name = next;
+                        *parser = super::parsing::JsonStreamParser::with_limits(limits);
+                    }
+                    if let Some(arguments) = delta.arguments {
+                        let (completed, _) = parser.feed(&arguments)?;
+                        calls.extend(completed.into_iter().map(|c| {
+                            let call = FunctionCall {
+                                name: name.clone(),
+                                arguments: c.json,
+                            };
+                            (index, call)
+                        }));
+                    }
+                }
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(calls)
+        })
+        .map_ok(|calls| futures::stream::iter(calls.into_iter().map(Ok)))
+        .try_flatten()
+}
+
+fn first_choice<T: Send, E: Send>(
+    items: impl Stream<Item = Result<(u32, T), E>> + Send,
+) -> impl Stream<Item = Result<T, E>> + Send {
+    items.try_filter_map(|(index, item)| async move { Ok((index == 0).then_some(item)) })
+}
+
+async fn fold_json_deltas(
+    deltas: impl Stream<Item = Result<JsonDelta, Box<dyn std::error::Error + Send + Sync>>>,
+    max_prose_bytes: usize,
+    mut flush: impl FnMut(&str),
+) -> Result<JsonResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let mut deltas = std::pin::pin!(deltas);
+
+    let mut string_response = String::new();
+    let mut json_response = None;
+    let mut json_span = None;
+
+    while let Some(delta) = deltas.next().await {
+        let delta = delta?;
+        string_response.push_str(&delta.prose);
+        if string_response.len() > max_prose_bytes {
+            flush(&string_response);
+            string_response.clear();
+        }
+        if delta.json.is_some() {
+            json_response = delta.json;
+            json_span = delta.json_span;
+        }
+    }
+
+    Ok(JsonResponse {
+        antecedent: string_response,
+        json: json_response,
+        json_span,
+    })
+}
+
+async fn fold_typed_json<T: serde::de::DeserializeOwned>(
+    deltas: impl Stream<Item = Result<JsonDelta, Box<dyn std::error::Error + Send + Sync>>>,
+) -> Result<(String, Option<T>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut deltas = std::pin::pin!(deltas);
+
+    let mut prose = String::new();
+    while let Some(delta) = deltas.next().await {
+        let delta = delta?;
+        prose.push_str(&delta.prose);
+        if let Some(json) = delta.json {
+            return Ok((prose, Some(serde_json::from_str(&json)?)));
+        }
+    }
+    Ok((prose, None))
+}
+
+/// Decodes `connection` into chat chunks.
+///
+/// The connection is dropped as soon as the stream ends, whether on `[DONE]`, a
+/// transport error or an unparseable chunk, so it's released even if the caller
+/// keeps the stream around after the error.
+fn chat_chunks(
+    connection: EventConnection,
+    skip_invalid_utf8: bool,
+    on_comment: Option<CommentHandler>,
+) -> impl Stream<Item = Result<ChatStream, Box<dyn std::error::Error + Send + Sync>>> + Send {
+    futures::stream::unfold(Some(connection), move |connection| {
+        let on_comment = on_comment.clone();
+        async move {
+            let mut connection = connection?;
+            while let Some(frame) = connection.next_frame().await {
+                match frame {
+                    Ok(frame) => {
+                        if let Some(CommentHandler(on_comment)) = &on_comment {
+                            frame
+                                .comments
+                                .iter()
+                                .for_each(|comment| on_comment(comment));
+                        }
+
+                        // Comment-only frames (keepalives) carry no data.
+                        let Some(data) = frame.data else {
+                            continue;
+                        };
+                        if data == "[DONE]" {
+                            return None;
+                        }
+
+                        return match serde_json::from_str::<ChatStream>(&data) {
+                            Ok(chunk) => Some((Ok(chunk), Some(connection))),
+                            Err(e) => Some((Err(e.into()), None)),
+                        };
+                    }
+                    Err(FrameError::Utf8(e)) if skip_invalid_utf8 => {
+                        log::warn!("skipping chat stream chunk: {e}");
+                    }
+                    Err(e) => return Some((Err(e.into()), None)),
+                }
+            }
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A completion with `choices` and no usage.
+    fn chat_response_with(choices: serde_json::Value) -> ChatResponse {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "object": "chat.completion",
+            "created": 0,
+            "choices": choices
+        }))
+        .unwrap()
+    }
+
+    /// A completion whose only choice is an assistant message with `content`.
+    fn chat_response(content: impl Into<serde_json::Value>) -> ChatResponse {
+        chat_response_with(serde_json::json!([{
+            "index": 0,
+            "message": { "role": "assistant", "content": content.into() },
+            "finish_reason": "stop"
+        }]))
+    }
+
+    /// A stream chunk with `choices`, and `usage` unless it's null.
+    fn stream_chunk_with(choices: serde_json::Value, usage: serde_json::Value) -> ChatStream {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "choices": choices,
+            "usage": usage
+        }))
+        .unwrap()
+    }
+
+    /// A stream chunk whose only choice carries `delta`.
+    fn stream_chunk(delta: serde_json::Value) -> ChatStream {
+        stream_chunk_with(
+            serde_json::json!([{ "index": 0, "delta": delta, "finish_reason": null }]),
+            serde_json::Value::Null,
+        )
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let request = || {
+            ChatRequest::builder()
+                .messages(vec![ChatMessage::new_user("Hello!", None)])
+                .temperature(0.7)
+                .build()
+        };
+
+        let key = request().cache_key();
+        assert_eq!(key, request().cache_key());
+        assert_eq!(
+            key,
+            r#"{"frequency_penalty":0,"messages":[{"content":"Hello!","role":"user"}],"model":"gpt-4-0613","stream":false,"temperature":0.7}"#
+        );
+    }
+
+    #[test]
+    fn test_token_estimate() {
+        let model = ChatModel::GPT4;
+
+        assert_eq!(
+            ChatMessage::new_user("Hello, world!", None).token_estimate(model),
+            8
+        );
+        assert_eq!(
+            ChatMessage::new_user("Hello, world!", Some("bob".into())).token_estimate(model),
+            10
+        );
+    }
+
+    #[test]
+    fn test_model_aliases() {
+        let model = |name: &str| serde_json::from_value::<ChatModel>(name.into()).unwrap();
+
+        assert_eq!(model("gpt-3.5-turbo"), ChatModel::GPT3);
+        assert_eq!(model("gpt-3.5-turbo-16k"), ChatModel::GPT3_16K);
+        assert_eq!(model("gpt-4-turbo"), ChatModel::GPT4_TURBO);
+        assert_eq!(
+            serde_json::to_value(model("gpt-4-turbo-preview")).unwrap(),
+            "gpt-4-1106-preview"
+        );
+        assert!(model("o3-mini").is_reasoning());
+        assert!(!model("o1-mini").supports_tools() && model("o1").supports_vision());
+    }
+
+    #[test]
+    fn test_reasoning_effort() {
+        let request = ChatRequest::builder()
+            .model(ChatModel::O3_MINI)
+            .user("Hi")
+            .reasoning_effort(ReasoningEffort::High)
+            .build();
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["model"], "o3-mini");
+        assert_eq!(json["reasoning_effort"], "high");
+        assert!(json.get("temperature").is_none());
+
+        let mut request = ChatRequest::builder()
+            .model(ChatModel::O1)
+            .user("Hi")
+            .temperature(0.2)
+            .build();
+        request.prepare().unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("temperature").is_none());
+
+        let json = serde_json::to_value(ChatRequest::builder().user("Hi").build()).unwrap();
+        assert!(json.get("reasoning_effort").is_none());
+    }
+
+    #[test]
+    fn test_function_builder() {
+        #[derive(JsonSchema)]
+        #[allow(dead_code)]
+        struct Todo {
+            item: String,
+        }
+
+        let built = Function::builder()
+            .name("add_todo")
+            .description("Adds a TODO")
+            .parameters_for::<Todo>()
+            .build();
+        let shortcut = Function::from_object::<Todo>("add_todo", Some("Adds a TODO".into()));
+        assert_eq!(
+            serde_json::to_value(built).unwrap(),
+            serde_json::to_value(shortcut).unwrap()
+        );
+
+        let bare = serde_json::to_value(Function::builder().name("ping").build()).unwrap();
+        assert_eq!(bare, serde_json::json!({ "name": "ping" }));
+    }
+
+    #[test]
+    fn test_stop() {
+        let request = ChatRequest::builder()
+            .user("Count")
+            .stop(["1", "", "2", "3", "4", "5"])
+            .build();
+        assert_eq!(
+            request.stop,
+            Some(vec!["1".into(), "2".into(), "3".into(), "4".into()])
+        );
+
+        let request = ChatRequest::builder().user("Count").stop_str("").build();
+        assert_eq!(request.stop, None);
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let request = ChatRequest::builder()
+            .system("Long context")
+            .user("Question")
+            .build()
+            .with_cache_control(0, CacheControl::Ephemeral);
+        assert_eq!(request.cache_control(0), Some(CacheControl::Ephemeral));
+        assert_eq!(request.cache_control(1), None);
+
+        assert!(!serde_json::to_string(&request)
+            .unwrap()
+            .contains("cache_control"));
+        assert_eq!(
+            request.to_json_with_cache_control()["messages"],
+            serde_json::json!([
+                { "role": "system", "content": "Long context", "cache_control": { "type": "ephemeral" } },
+                { "role": "user", "content": "Question" },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_usage_sum() {
+        let usage = |prompt_tokens, completion_tokens| ChatUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        };
+
+        let mut total: ChatUsage = [usage(10, 5), usage(20, 7)].into_iter().sum();
+        total += usage(1, 1);
+        assert_eq!(total, usage(31, 13));
+        assert_eq!(total.total(), total.total_tokens);
+
+        let total = usage(u32::MAX - 1, 0) + usage(5, 1);
+        assert_eq!(total.prompt_tokens, u32::MAX);
+        assert_eq!(total.total(), u32::MAX);
+    }
+
+    #[test]
+    fn test_function_schema_order() {
+        #[derive(JsonSchema)]
+        #[allow(dead_code)]
+        struct Zeta {
+            value: u32,
+        }
+
+        #[derive(JsonSchema)]
+        #[allow(dead_code)]
+        struct Args {
+            zeta: Zeta,
+            alpha: String,
+        }
+
+        let json = serde_json::to_string(&Function::from_object::<Args>("f", None)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::to_string(&Function::from_object::<Args>("f", None)).unwrap()
+        );
+        assert!(json.find("\"alpha\"").unwrap() < json.find("\"zeta\"").unwrap());
+    }
+
+    #[test]
+    fn test_handle_function_call() {
+        #[derive(Deserialize)]
+        struct Add {
+            a: i32,
+            b: i32,
+        }
+
+        let call = FunctionCall {
+            name: "add".into(),
+            arguments: r#"{"a": 1, "b": 2}"#.into(),
+        };
+        let reply = handle_function_call(&call, |args: Add| Ok::<_, HandlerError>(args.a + args.b));
+        assert_eq!(reply.unwrap(), ChatMessage::new_function("3", "add"));
+
+        let reply = handle_function_call(&call, |_: Add| Err::<(), _>("no adding today"));
+        assert!(matches!(reply, Err(HandlerError::Handler(_))));
+
+        let reply = handle_function_call(&call, |_: String| Ok::<_, HandlerError>(()));
+        assert!(matches!(reply, Err(HandlerError::Arguments(_))));
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_releases_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let _ = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                      transfer-encoding: chunked\r\n\r\n\
+                      10\r\ndata: {invalid\n\n\r\n",
+                )
+                .await
+                .unwrap();
+            // The body is never finished, so only the client closing the socket
+            // ends this read.
+            socket.read(&mut buf).await.unwrap()
+        });
+
+        let connection = EventConnection::open(Client::new().get(format!("http://{address}")))
+            .await
+            .unwrap();
+        let mut chunks = Box::pin(chat_chunks(connection, false, None));
+        assert!(chunks.next().await.unwrap().is_err());
+
+        // The stream is still alive here, but the connection must already be gone.
+        let read = tokio::time::timeout(std::time::Duration::from_secs(5), server)
+            .await
+            .expect("connection was not released")
+            .unwrap();
+        assert_eq!(read, 0);
+        assert!(chunks.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_request_raw_body() {
+        use crate::api::client::ApiConfig;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut bodies = Vec::new();
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // Read until the headers and the whole body have arrived.
+                let body = loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .unwrap()
+                        .parse::<usize>()
+                        .unwrap();
+                    if body.len() >= length {
+                        break body.to_string();
+                    }
+                };
+                bodies.push(serde_json::from_str::<serde_json::Value>(&body).unwrap());
+                let body = serde_json::to_string(&chat_response("Hi")).unwrap();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            bodies
+        });
+
+        let config =
+            ApiConfig::new(Client::new(), "key").with_base_url(format!("http://{address}"));
+        let request = || {
+            ChatRequest::builder()
+                .model(ChatModel::O1)
+                .user("Hi")
+                .temperature(0.2)
+                .build()
+        };
+        request().request_config(&config).await.unwrap();
+        request().request_raw_config(&config).await.unwrap();
+
+        let bodies = server.await.unwrap();
+        assert!(bodies[0].get("temperature").is_none());
+        assert_eq!(bodies[0], bodies[1]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_tee() {
+        use crate::api::backend::MockBackend;
+
+        let backend = MockBackend::new();
+        backend.push_content_stream(["Here: {\"a\"", ": 1}"]);
+
+        let mut trace = Vec::new();
+        let response = ChatRequest::builder()
+            .user("Hi")
+            .stream(true)
+            .build()
+            .stream_tee_with(&backend, &mut trace)
+            .await
+            .unwrap();
+        assert_eq!(response.json.as_deref(), Some("{\"a\": 1}"));
+
+        let trace = String::from_utf8(trace).unwrap();
+        let lines: Vec<_> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let chunk: ChatStream = serde_json::from_str(lines[1]).unwrap();
+        assert!(matches!(chunk.delta(), Some(ChatDelta::Content(c)) if c == ": 1}"));
+        assert!(lines[0].contains(r#""delta":{"content":"Here: {\"a\""}"#));
+    }
+
+    #[tokio::test]
+    async fn test_stream_collect() {
+        use crate::api::backend::MockBackend;
+        use serde_json::json;
+
+        let backend = MockBackend::new();
+        backend.push_stream([
+            stream_chunk(json!({ "role": "assistant", "content": "" })),
+            stream_chunk(json!({ "content": "Hi" })),
+            stream_chunk_with(
+                json!([{ "index": 0, "delta": {}, "finish_reason": "stop" }]),
+                json!(null),
+            ),
+            stream_chunk_with(
+                json!([]),
+                json!({ "prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6 }),
+            ),
+        ]);
+
+        let response = ChatRequest::builder()
+            .user("Hi")
+            .stream(true)
+            .stream_options(StreamOptions {
+                include_usage: true,
+            })
+            .build()
+            .stream_collect_with(&backend)
+            .await
+            .unwrap();
+        assert_eq!(response.message(), Some(&ChatMessage::new_assistant("Hi")));
+        assert_eq!(response.choices[0].finish_reason, "stop");
+        assert_eq!(response.tokens().total_tokens, 6);
+    }
+
+    #[test]
+    fn test_max_completion_tokens() {
+        let mut request = ChatRequest::builder()
+            .model(ChatModel::O1)
+            .user("Hi")
+            .max_tokens(100)
+            .build();
+        request.use_model_token_limit().unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["max_completion_tokens"], 100);
+        assert!(json.get("max_tokens").is_none());
+
+        let mut request = ChatRequest::builder()
+            .user("Hi")
+            .max_completion_tokens(100)
+            .build();
+        request.use_model_token_limit().unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["max_tokens"], 100);
+        assert!(json.get("max_completion_tokens").is_none());
+
+        let mut request = ChatRequest::builder()
+            .user("Hi")
+            .max_tokens(100)
+            .max_completion_tokens(100)
+            .build();
+        assert_eq!(
+            request.validate().unwrap_err(),
+            vec![ChatValidationError::BothTokenLimits]
+        );
+        assert!(request.use_model_token_limit().is_err());
+    }
+
+    #[test]
+    fn test_prediction() {
+        let request = ChatRequest::builder()
+            .user("Rename x to y: let x = 1;")
+            .prediction(Prediction::content("let y = 1;"))
+            .build();
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["prediction"],
+            serde_json::json!({ "type": "content", "content": "let y = 1;" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_comments() {
+        let chunk = r#"{"id":"1","object":"chat.completion.chunk","created":0,"choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":null}]}"#;
+        let body = format!(": keepalive\n\ndata: {chunk}\n\n: still here\n\ndata: [DONE]\n\n");
+        let connection = EventConnection::from_response(http::Response::new(body).into());
+
+        let comments = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = comments.clone();
+        let on_comment = CommentHandler(Arc::new(move |c: &str| {
+            seen.lock().unwrap().push(c.to_string())
+        }));
+
+        let chunks: Vec<_> = chat_chunks(connection, false, Some(on_comment))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(*comments.lock().unwrap(), vec!["keepalive", "still here"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_invalid_utf8() {
+        let chunk = |content: &str| {
+            format!(
+                r#"data: {{"id":"1","object":"chat.completion.chunk","created":0,"choices":[{{"index":0,"delta":{{"content":"{content}"}},"finish_reason":null}}]}}"#
+            )
+        };
+        // The last chunk has no closing blank line.
+        let mut body = format!("{}\n\n", chunk("Hello")).into_bytes();
+        body.extend_from_slice(b"data: \xff\xfe\n\n");
+        body.extend_from_slice(chunk(" world").as_bytes());
+        let connection =
+            || EventConnection::from_response(http::Response::new(body.clone()).into());
+
+        let contents: Vec<_> = chat_chunks(connection(), true, None)
+            .map_ok(|chunk| match chunk.delta() {
+                Some(ChatDelta::Content(content)) => content,
+                _ => String::new(),
+            })
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(contents, vec!["Hello", " world"]);
+
+        let results: Vec<_> = chat_chunks(connection(), false, None).collect().await;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(results[1]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("wasn't valid UTF-8"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_function_calls() {
+        use crate::api::backend::MockBackend;
+        use serde_json::json;
+
+        let backend = MockBackend::new();
+        backend.push_stream([
+            stream_chunk(json!({ "role": "assistant", "content": null, "function_call": { "name": "add", "arguments": "" } })),
+            stream_chunk(json!({ "function_call": { "arguments": "{\"a\"" } })),
+            stream_chunk(json!({ "function_call": { "arguments": ": 1}" } })),
+            stream_chunk(json!({ "function_call": { "name": "sub", "arguments": "{}" } })),
+            stream_chunk(json!({})),
+        ]);
+
+        let calls: Vec<_> = ChatRequest::builder()
+            .user("Hi")
+            .stream(true)
+            .build()
+            .stream_function_calls_with(&backend)
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            calls,
+            vec![
+                FunctionCall {
+                    name: "add".into(),
+                    arguments: "{\"a\": 1}".into()
+                },
+                FunctionCall {
+                    name: "sub".into(),
+                    arguments: "{}".into()
+                },
+            ]
+        );
+
+        let choice = |index: u32, function_call: serde_json::Value| {
+            stream_chunk_with(
+                json!([{
+                    "index": index,
+                    "delta": { "function_call": function_call },
+                    "finish_reason": null
+                }]),
+                json!(null),
+            )
+        };
+        let interleaved = || {
+            [
+                choice(0, json!({ "name": "add", "arguments": "{\"a\"" })),
+                choice(1, json!({ "name": "add", "arguments": "{\"a\": 2" })),
+                choice(0, json!({ "arguments": ": 1}" })),
+                choice(1, json!({ "arguments": "}" })),
+            ]
+        };
+        backend.push_stream(interleaved());
+        backend.push_stream(interleaved());
+
+        let request = || ChatRequest::builder().user("Hi").stream(true).n(2).build();
+        let calls: Vec<_> = request()
+            .stream_choice_function_calls_with(&backend)
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let add = |arguments: &str| FunctionCall {
+            name: "add".into(),
+            arguments: arguments.into(),
+        };
+        assert_eq!(calls, vec![(0, add("{\"a\": 1}")), (1, add("{\"a\": 2}"))]);
+
+        let calls: Vec<_> = request()
+            .stream_function_calls_with(&backend)
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(calls, vec![add("{\"a\": 1}")]);
+
+        let first = stream_chunk(json!({ "role": "assistant", "content": "" }));
+        assert!(matches!(first.delta(), Some(ChatDelta::Role(role)) if role == "assistant"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_tool_calls() {
+        use crate::api::backend::MockBackend;
+        use serde_json::json;
+
+        let tool_call = |index: u32, function: serde_json::Value| json!({ "tool_calls": [{ "index": index, "function": function }] });
+        let backend = MockBackend::new();
+        backend.push_stream([
+            stream_chunk(json!({
+                "role": "assistant",
+                "tool_calls": [
+                    { "index": 0, "id": "call_0", "type": "function", "function": { "name": "add", "arguments": "" } },
+                    { "index": 1, "id": "call_1", "type": "function", "function": { "name": "sub", "arguments": "{\"b\"" } }
+                ]
+            })),
+            stream_chunk(tool_call(0, json!({ "arguments": "{\"a\"" }))),
+            stream_chunk(tool_call(1, json!({ "arguments": ": 2}" }))),
+            stream_chunk(tool_call(0, json!({ "arguments": ": 1}" }))),
+            stream_chunk(json!({})),
+        ]);
+
+        let calls: Vec<_> = ChatRequest::builder()
+            .user("Hi")
+            .stream(true)
+            .build()
+            .stream_function_calls_with(&backend)
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            calls,
+            vec![
+                FunctionCall {
+                    name: "sub".into(),
+                    arguments: "{\"b\": 2}".into()
+                },
+                FunctionCall {
+                    name: "add".into(),
+                    arguments: "{\"a\": 1}".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_response_kind() {
+        let response = JsonResponse {
+            antecedent: "Sure: ".into(),
+            json: Some(r#"{"a": 1}"#.into()),
+            json_span: Some(6..14),
+        };
+        assert!(response.has_json() && !response.is_pure_prose());
+        assert_eq!(
+            response.parse_or_err::<serde_json::Value>().unwrap(),
+            serde_json::json!({ "a": 1 })
+        );
+
+        let response = JsonResponse {
+            antecedent: "I can't help with that.".into(),
+            json: None,
+            json_span: None,
+        };
+        assert!(response.is_pure_prose());
+        assert_eq!(
+            response
+                .parse_or_err::<serde_json::Value>()
+                .unwrap_err()
+                .to_string(),
+            "the response contained no JSON object"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_json_bounded() {
+        use crate::api::backend::MockBackend;
+
+        let backend = MockBackend::new();
+        backend.push_content_stream(["Twelve bytes", " ok", ", then: ", "{\"a\": 1}"]);
+
+        let request = ChatRequest::builder().user("Hi").stream(true).build();
+        let deltas = json_deltas(
+            request.stream_with(&backend).unwrap(),
+            JsonLimits::default(),
+        );
+        let mut flushed = Vec::new();
+        let response = fold_json_deltas(deltas, 10, |prose| flushed.push(prose.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(flushed, vec!["Twelve bytes", " ok, then: "]);
+        assert_eq!(response.antecedent, "");
+        assert_eq!(response.json.as_deref(), Some("{\"a\": 1}"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_json_limit_keeps_prose() {
+        use crate::api::backend::MockBackend;
+
+        let backend = MockBackend::new();
+        backend.push_content_stream(["Sure. ", "Here: {\"a\": \"0123456789abcdef\"}"]);
+
+        let request = ChatRequest::builder().user("Hi").stream(true).build();
+        let limits = JsonLimits {
+            max_depth: 2,
+            max_bytes: 16,
+        };
+        let deltas: Vec<_> = json_deltas(request.stream_with(&backend).unwrap(), limits)
+            .collect()
+            .await;
+
+        let prose: Vec<_> = deltas[..2]
+            .iter()
+            .map(|delta| delta.as_ref().unwrap().prose.as_str())
+            .collect();
+        assert_eq!(prose, vec!["Sure. ", "Here: "]);
+        let error = deltas[2].as_ref().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<JsonLimitError>().unwrap().kind,
+            JsonLimitKind::Bytes(16)
+        );
+        assert_eq!(deltas.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_collect_json_stream() {
+        use crate::api::backend::MockBackend;
+
+        let backend = MockBackend::new();
+        backend
+            .push_content_stream(["First {\"a\"", ": 1}, then {\"b\": [2]}", " and {", "}."])
+            .push_content_stream(["Bad: {\"a\": one}"]);
+
+        let request = || ChatRequest::builder().user("Hi").stream(true).build();
+        let (objects, prose) = request().collect_json_stream_with(&backend).await.unwrap();
+        assert_eq!(
+            objects,
+            vec![
+                serde_json::json!({ "a": 1 }),
+                serde_json::json!({ "b": [2] }),
+                serde_json::json!({})
+            ]
+        );
+        assert_eq!(prose, "First , then  and .");
+
+        assert!(request().collect_json_stream_with(&backend).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_json_typed() {
+        use crate::api::backend::MockBackend;
+
+        #[derive(Deserialize)]
+        struct Answer {
+            a: i32,
+        }
+
+        let backend = MockBackend::new();
+        backend.push_content_stream(["Here: {\"a\"", ": 2}"]);
+
+        let (prose, answer) = ChatRequest::builder()
+            .user("Give me a")
+            .stream(true)
+            .build()
+            .stream_json_typed_with::<Answer>(&backend)
+            .await
+            .unwrap();
+        assert_eq!(prose, "Here: ");
+        assert_eq!(answer.unwrap().a, 2);
+    }
+
+    #[tokio::test]
+    async fn test_repair_json() {
+        use crate::api::backend::MockBackend;
+
+        #[derive(Deserialize)]
+        struct Answer {
+            a: i32,
+        }
+
+        let backend = MockBackend::new();
+        backend
+            .push_content_stream(["{\"a\": one}"])
+            .push_content_stream(["Fixed: {\"a\": 1}"]);
+
+        let request = || ChatRequest::builder().user("Give me a").build();
+        let answer: Answer = request().repair_json(&backend, 2).await.unwrap();
+        assert_eq!(answer.a, 1);
+
+        backend.push_content_stream(["{\"a\": one}"]);
+        assert!(request().repair_json::<Answer>(&backend, 1).await.is_err());
+    }
+
+    #[test]
+    fn test_audio_response() {
+        let response: ChatResponse = serde_json::from_str(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1700000000,
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "audio": {
+                            "id": "audio_123",
+                            "data": "UklGRg==",
+                            "transcript": "Hello there!",
+                            "expires_at": 1700003600
+                        }
+                    },
+                    "finish_reason": "stop"
+                }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 20, "total_tokens": 30 }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(response.audio_transcript(), Some("Hello there!"));
+        assert_eq!(response.message().unwrap().content(), None);
+    }
+
+    #[test]
+    fn test_logprobs() {
+        let response = chat_response_with(serde_json::json!([{
+            "index": 0,
+            "message": { "role": "assistant", "content": "Hi" },
+            "logprobs": {
+                "content": [{
+                    "token": "Hi",
+                    "logprob": -0.25,
+                    "bytes": [72, 105],
+                    "top_logprobs": [
+                        { "token": "Hi", "logprob": -0.25, "bytes": [72, 105] },
+                        { "token": "Hey", "logprob": -1.5, "bytes": null }
+                    ]
+                }],
+                "refusal": null
+            },
+            "finish_reason": "stop"
+        }]));
+
+        let content = response.logprobs().unwrap().content.as_ref().unwrap();
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[0].token, "Hi");
+        assert_eq!(content[0].logprob, -0.25);
+        assert_eq!(content[0].bytes.as_deref(), Some(&b"Hi"[..]));
+        assert_eq!(
+            content[0].top_logprobs[1],
+            TopLogprob {
+                token: "Hey".into(),
+                logprob: -1.5,
+                bytes: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_assistant_text() {
+        let parts = chat_response(serde_json::json!([
+            { "type": "text", "text": "Hello, " },
+            { "type": "image_url", "image_url": { "url": "https://example.com/a.png" } },
+            { "type": "text", "text": "world!" }
+        ]));
+        assert_eq!(parts.assistant_text().as_deref(), Some("Hello, world!"));
+        assert_eq!(parts.message().unwrap().content(), None);
+
+        let plain = chat_response("Hi");
+        assert_eq!(plain.assistant_text().as_deref(), Some("Hi"));
+    }
+
+    #[test]
+    fn test_to_type_verbose() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Item {
+            name: String,
+        }
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Args {
+            items: Vec<Item>,
+        }
+
+        let call = FunctionCall {
+            name: "add_items".into(),
+            arguments: r#"{"items": [{"name": "a"}, {"name": 2}]}"#.into(),
+        };
+        let error = call.to_type_verbose::<Args>().unwrap_err();
+        assert_eq!(error.path, "items[1].name");
+        assert!(error
+            .to_string()
+            .starts_with("invalid arguments for add_items at items[1].name: invalid type"));
+        assert!(error
+            .to_string()
+            .ends_with(&format!("(arguments: {})", call.arguments)));
+
+        let call = FunctionCall {
+            name: "add_items".into(),
+            arguments: r#"{"items": []} {"items": []}"#.into(),
+        };
+        assert!(call.to_type::<Args>().is_err());
+        let error = call.to_type_verbose::<Args>().unwrap_err();
+        assert_eq!(error.path, ".");
+        assert!(error.source.to_string().starts_with("trailing characters"));
+    }
+
+    #[test]
+    fn test_extract_json() {
+        let response: ChatResponse = serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "object": "chat.completion",
+            "created": 0,
+            "choices": [
+                {
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Use `{x}`: {\"a\": 1} ok" },
+                    "finish_reason": "stop"
+                },
+                {
+                    "index": 1,
+                    "message": { "role": "assistant", "content": "No JSON here." },
+                    "finish_reason": "stop"
+                },
+                {
+                    "index": 2,
+                    "message": { "role": "assistant", "content": "```json\n{\"a\": 2}\n```" },
+                    "finish_reason": "stop"
+                }
+            ],
+            "usage": { "prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2 }
+        }))
+        .unwrap();
+
+        assert_eq!(response.extract_json(), Some(serde_json::json!({ "a": 1 })));
+        assert_eq!(
+            response.extract_json_choices(),
+            vec![
+                Some(serde_json::json!({ "a": 1 })),
+                None,
+                Some(serde_json::json!({ "a": 2 }))
+            ]
+        );
+
+        #[derive(Deserialize)]
+        struct A {
+            a: i32,
+        }
+        assert_eq!(response.extract::<A>().unwrap().unwrap().a, 1);
+    }
+
+    #[test]
+    fn test_missing_usage() {
+        assert_eq!(chat_response("Hi").tokens(), ChatUsage::default());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ChatRequest::from("Hi").validate().is_ok());
+
+        let request = ChatRequest::builder()
+            .functions(vec![Function::new("add", None)])
+            .function_call(FunctionCallType::Name("sub".into()))
+            .top_logprobs(30)
+            .build();
+        assert_eq!(
+            request.validate().unwrap_err(),
+            vec![
+                ChatValidationError::NoMessages,
+                ChatValidationError::UnknownFunction("sub".into()),
+                ChatValidationError::TopLogprobsWithoutLogprobs,
+                ChatValidationError::TopLogprobsOutOfRange(30),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_builder_messages() {
+        let request = ChatRequest::builder()
+            .user("First")
+            .system("Be brief.")
+            .assistant("Second")
+            .system("Be kind.")
+            .build();
+
+        assert_eq!(
+            request.messages,
+            vec![
+                ChatMessage::new_system("Be brief."),
+                ChatMessage::new_system("Be kind."),
+                ChatMessage::new_user("First", None),
+                ChatMessage::new_assistant("Second"),
+            ]
+        );
+
+        let request = ChatRequest::from("Hello");
+        assert_eq!(request.messages, vec![ChatMessage::new_user("Hello", None)]);
+        assert_eq!(request.model, ChatModel::GPT4);
+    }
+
+    #[tokio::test]
+    async fn test_empty_request() {
+        let error = ChatRequest::builder()
+            .build()
+            .request(&Client::new(), "key")
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "the request has no messages");
+
+        let error = ChatRequest::builder()
+            .stream(true)
+            .build()
+            .stream(&Client::new(), "key")
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "the request has no messages");
+    }
+
+    #[test]
+    fn test_normalize_messages() {
+        let messages = vec![
+            ChatMessage::new_system("A"),
+            ChatMessage::new_system("B"),
+            ChatMessage::new_user("C", None),
+            ChatMessage::new_user("D", None),
+            ChatMessage::new_user("E", Some("eve".into())),
+            ChatMessage::new_function("F", "f"),
+            ChatMessage::new_function("G", "f"),
+            ChatMessage::new_assistant("H"),
+            ChatMessage::new_assistant("I"),
+        ];
+
+        assert_eq!(
+            normalize_messages_with_separator(messages, " "),
+            vec![
+                ChatMessage::new_system("A B"),
+                ChatMessage::new_user("C D", None),
+                ChatMessage::new_user("E", Some("eve".into())),
+                ChatMessage::new_function("F", "f"),
+                ChatMessage::new_function("G", "f"),
+                ChatMessage::new_assistant("H I"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_race_requests() {
+        use std::time::Duration;
+
+        /// Echoes the user message after the delay in milliseconds it starts
+        /// with, failing without a user message.
+        struct DelayedBackend;
+
+        impl ChatBackend for DelayedBackend {
+            fn complete(
+                &self,
+                request: ChatRequest,
+            ) -> BoxFuture<'_, Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>>>
+            {
+                async move {
+                    let content = match request.messages.first() {
+                        Some(ChatMessage::User { content, .. }) => content.clone(),
+                        _ => return Err("no user message".into()),
+                    };
+                    let (delay, content) = content.split_once(' ').unwrap();
+                    tokio::time::sleep(Duration::from_millis(delay.parse()?)).await;
+                    Ok(chat_response(content))
+                }
+                .boxed()
+            }
+
+            fn stream(
+                &self,
+                _request: ChatRequest,
+            ) -> Result<ChunkStream, Box<dyn std::error::Error + Send + Sync>> {
+                Err("not supported".into())
+            }
+        }
+
+        let request = |content: Option<&str>| match content {
+            Some(content) => ChatRequest::builder().user(content).build(),
+            None => ChatRequest::builder().build(),
+        };
+
+        let response = race_requests_with(
+            vec![
+                request(Some("200 slow")),
+                request(None),
+                request(Some("20 fast")),
+            ],
+            &DelayedBackend,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.assistant_text().as_deref(), Some("fast"));
+
+        let error = race_requests_with(vec![request(None), request(None)], &DelayedBackend)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no user message");
+        assert!(race_requests_with(Vec::new(), &DelayedBackend)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls() {
+        use crate::api::backend::MockBackend;
+
+        let response = chat_response_with(serde_json::json!([{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "weather", "arguments": "{\"city\":\"Oslo\"}" }
+                }]
+            },
+            "finish_reason": "tool_calls"
+        }]));
+        let call = &response.tool_calls()[0];
+        assert_eq!((call.id(), call.tool_type()), ("call_1", "function"));
+        assert_eq!(call.function().name(), "weather");
+
+        let reply = ChatMessage::new_tool("Sunny", call.id());
+        assert_eq!(
+            serde_json::to_value(&reply).unwrap(),
+            serde_json::json!({ "role": "tool", "content": "Sunny", "tool_call_id": "call_1" })
+        );
+
+        // Fragments of two calls, interleaved, with the id only on each first fragment.
+        let chunk = |tool_calls: serde_json::Value| {
+            stream_chunk(serde_json::json!({ "tool_calls": tool_calls }))
+        };
+        let backend = MockBackend::new();
+        backend.push_stream([
+            chunk(serde_json::json!([
+                { "index": 0, "id": "call_a", "type": "function", "function": { "name": "a", "arguments": "" } }
+            ])),
+            chunk(serde_json::json!([
+                { "index": 1, "id": "call_b", "type": "function", "function": { "name": "b", "arguments": "{\"x\"" } }
+            ])),
+            chunk(serde_json::json!([{ "index": 0, "function": { "arguments": "{}" } }])),
+            chunk(serde_json::json!([{ "index": 1, "function": { "arguments": ":1}" } }])),
+        ]);
+
+        let response = ChatRequest::builder()
+            .user("Hi")
+            .stream(true)
+            .build()
+            .stream_collect_with(&backend)
+            .await
+            .unwrap();
+        let calls = response.tool_calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!((calls[0].id(), calls[0].index()), ("call_a", Some(0)));
+        assert_eq!(calls[0].function().arguments(), "{}");
+        assert_eq!(calls[1].id(), "call_b");
+        assert_eq!(calls[1].function().arguments(), "{\"x\":1}");
+
+        // Collected calls can be sent back as history, without their index.
+        let message = response.message().unwrap();
+        let json = serde_json::to_value(message).unwrap();
+        assert!(json["tool_calls"][0].get("index").is_none());
+        let round_trip: ChatMessage = serde_json::from_value(json).unwrap();
+        match round_trip {
+            ChatMessage::Assistant {
+                content: AssistantContent::ToolCalls { tool_calls, .. },
+                ..
+            } => {
+                assert_eq!(tool_calls[0].index(), None);
+                assert_eq!(tool_calls[0].id(), "call_a");
+                assert_eq!(tool_calls[1].function(), calls[1].function());
+            }
+            message => panic!("expected tool calls, got {message:?}"),
+        }
     }
 }
@@ -0,0 +1,266 @@
+use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, RequestBuilder};
+use std::time::Duration;
+
+use super::hooks::Hooks;
+
+/// Generous enough for long streamed completions, since the timeout covers the whole response.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Builds a [`Client`] configured for OpenAI's API: pooled keep-alive connections,
+/// gzip-compressed responses, HTTP/2 where the server offers it over TLS (ALPN),
+/// and a default request timeout.
+///
+/// Build one client and share it across requests (it's cheap to clone), rather
+/// than creating a client per request, so that connections are reused.
+pub fn default_client() -> Client {
+    default_client_with_timeout(DEFAULT_TIMEOUT)
+}
+
+/// Like [`default_client`], but with a custom timeout covering each whole request,
+/// including the time spent streaming the response body.
+pub fn default_client_with_timeout(timeout: Duration) -> Client {
+    Client::builder()
+        .timeout(timeout)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(POOL_IDLE_TIMEOUT)
+        .gzip(true)
+        .build()
+        .expect("error building HTTP client")
+}
+
+/// The default [`ApiConfig::base_url`].
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// How to reach the OpenAI API: the client and key to send requests with, where
+/// to send them, and the [`Hooks`] to call around each one.
+#[derive(Clone)]
+pub struct ApiConfig {
+    pub client: Client,
+    pub api_key: String,
+    /// [`OPENAI_BASE_URL`] unless requests go through a proxy or a compatible server.
+    pub base_url: String,
+    pub hooks: Hooks,
+}
+
+impl std::fmt::Debug for ApiConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiConfig")
+            .field("base_url", &self.base_url)
+            .field("hooks", &self.hooks)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ApiConfig {
+    pub fn new(client: Client, api_key: impl Into<String>) -> Self {
+        Self {
+            client,
+            api_key: api_key.into(),
+            base_url: OPENAI_BASE_URL.into(),
+            hooks: Hooks::default(),
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// A JSON `POST` to `path` under the base URL, authenticated with the key.
+    pub(crate) fn post(&self, path: &str) -> RequestBuilder {
+        self.client
+            .post(format!("{}/{path}", self.base_url.trim_end_matches('/')))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", self.api_key))
+    }
+}
+
+/// Far shorter than any key OpenAI issues, so only keys cut off when pasting are
+/// rejected.
+const MIN_KEY_LEN: usize = 20;
+
+/// Why [`validate_api_key`] rejected a key. Never includes the key itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyError {
+    Empty,
+    /// The key has leading or trailing whitespace, usually from pasting it.
+    Untrimmed,
+    /// The key doesn't start with `sk-`, as user, project (`sk-proj-`) and
+    /// service account keys do.
+    MissingPrefix,
+    TooShort {
+        len: usize,
+    },
+    /// The key contains a character other than ASCII letters, digits, `-` and `_`.
+    InvalidCharacter {
+        index: usize,
+    },
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "API key is empty"),
+            Self::Untrimmed => write!(f, "API key has leading or trailing whitespace"),
+            Self::MissingPrefix => write!(f, "API key doesn't start with \"sk-\""),
+            Self::TooShort { len } => write!(
+                f,
+                "API key is too short ({len} characters), it may have been truncated"
+            ),
+            Self::InvalidCharacter { index } => {
+                write!(f, "API key has an invalid character at index {index}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Checks that `api_key` looks like an OpenAI key, to fail at startup with a
+/// clear error rather than with a `401` from the first request.
+///
+/// Only the shape is checked, not whether the key is valid. Requests don't call
+/// this, so keys for gateways in other formats keep working.
+pub fn validate_api_key(api_key: &str) -> Result<(), KeyError> {
+    if api_key.is_empty() {
+        return Err(KeyError::Empty);
+    }
+    if api_key.trim() != api_key {
+        return Err(KeyError::Untrimmed);
+    }
+    if !api_key.starts_with("sk-") {
+        return Err(KeyError::MissingPrefix);
+    }
+    if let Some(index) = api_key
+        .chars()
+        .position(|c| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+    {
+        return Err(KeyError::InvalidCharacter { index });
+    }
+    if api_key.len() < MIN_KEY_LEN {
+        return Err(KeyError::TooShort { len: api_key.len() });
+    }
+    Ok(())
+}
+
+/// Adds caller-supplied `headers` (e.g. for routing through a gateway) to a request.
+///
+/// The built-in `Content-Type` and credential headers take precedence, so any of
+/// those in `headers` are dropped.
+pub(crate) fn with_extra_headers(request: RequestBuilder, headers: &HeaderMap) -> RequestBuilder {
+    if headers.is_empty() {
+        return request;
+    }
+
+    let mut extra = headers.clone();
+    for name in [CONTENT_TYPE.as_str(), AUTHORIZATION.as_str(), "api-key"] {
+        if extra.remove(name).is_some() {
+            log::warn!("ignoring custom {name:?} header in favor of the built-in one");
+        }
+    }
+    request.headers(extra)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_extra_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant-id", "acme".parse().unwrap());
+        headers.insert(AUTHORIZATION, "Bearer other".parse().unwrap());
+
+        let request = Client::new()
+            .post("https://api.openai.com/v1/chat/completions")
+            .header(AUTHORIZATION, "Bearer key");
+        let request = with_extra_headers(request, &headers).build().unwrap();
+
+        assert_eq!(request.headers()["x-tenant-id"], "acme");
+        assert_eq!(request.headers()[AUTHORIZATION], "Bearer key");
+        assert_eq!(request.headers().get_all(AUTHORIZATION).iter().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_default_client_with_timeout() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // "hello", gzip-compressed.
+        const GZIP_HELLO: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\xff\xcb\x48\xcd\xc9\xc9\x07\x00\x86\xa6\x10\x36\x05\x00\x00\x00";
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    // `/slow` requests never get a response.
+                    while let Ok(n @ 1..) = socket.read(&mut buf).await {
+                        if buf[..n].starts_with(b"GET /slow") {
+                            continue;
+                        }
+                        let head = format!(
+                            "HTTP/1.1 200 OK\r\ncontent-encoding: gzip\r\ncontent-length: {}\r\n\r\n",
+                            GZIP_HELLO.len()
+                        );
+                        socket.write_all(head.as_bytes()).await.unwrap();
+                        socket.write_all(GZIP_HELLO).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let client = default_client_with_timeout(Duration::from_millis(200));
+        let body = client
+            .get(format!("http://{address}/gzip"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "hello");
+
+        let error = client
+            .get(format!("http://{address}/slow"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(error.is_timeout());
+    }
+
+    #[test]
+    fn test_validate_api_key() {
+        let key = format!("sk-proj-{}", "a1B2_c3D4-".repeat(4));
+        assert_eq!(validate_api_key(&key), Ok(()));
+
+        assert_eq!(validate_api_key(""), Err(KeyError::Empty));
+        assert_eq!(
+            validate_api_key(&format!("{key}\n")),
+            Err(KeyError::Untrimmed)
+        );
+        assert_eq!(
+            validate_api_key("Bearer sk-abc"),
+            Err(KeyError::MissingPrefix)
+        );
+        assert_eq!(
+            validate_api_key("sk-abc123"),
+            Err(KeyError::TooShort { len: 9 })
+        );
+        assert_eq!(
+            validate_api_key(&format!("sk-\"{key}")),
+            Err(KeyError::InvalidCharacter { index: 3 })
+        );
+        assert!(!KeyError::TooShort { len: 9 }.to_string().contains("sk-"));
+    }
+}
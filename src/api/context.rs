@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+use typed_builder::TypedBuilder;
+
+use super::chat::ChatModel;
+use super::template::{PromptTemplate, TemplateError};
+use super::tokens::count_tokens;
+
+/// How [`format_context`] lays out retrieved results for a prompt.
+#[derive(Debug, Clone, TypedBuilder)]
+pub struct ContextFormat {
+    /// Rendered once per result, with `{{text}}`, `{{score}}` (to two decimals) and
+    /// `{{rank}}` (starting at 1) available.
+    #[builder(
+        default = PromptTemplate::new("{{text}}"),
+        setter(transform = |template: impl Into<String>| PromptTemplate::new(template))
+    )]
+    template: PromptTemplate,
+    #[builder(default = "\n\n".into(), setter(into))]
+    separator: String,
+    /// Token budget for the whole block. Results are dropped, lowest score first,
+    /// until it fits.
+    #[builder(default, setter(strip_option))]
+    max_tokens: Option<usize>,
+    /// Whose tokenizer counts against `max_tokens`.
+    #[builder(default = ChatModel::GPT4)]
+    model: ChatModel,
+}
+
+impl ContextFormat {
+    /// Joins `results` (e.g. from [`knn_search`](super::embeddings::knn_search)) in
+    /// the order given.
+    pub fn format(&self, results: &[(&str, f32)]) -> Result<String, TemplateError> {
+        let mut rendered = results
+            .iter()
+            .enumerate()
+            .map(|(index, (text, score))| {
+                let vars = HashMap::from([
+                    ("text", text.to_string()),
+                    ("score", format!("{score:.2}")),
+                    ("rank", (index + 1).to_string()),
+                ]);
+                Ok((self.template.render(&vars)?, *score))
+            })
+            .collect::<Result<Vec<_>, TemplateError>>()?;
+
+        let join = |rendered: &[(String, f32)]| {
+            let parts: Vec<_> = rendered.iter().map(|(text, _)| text.as_str()).collect();
+            parts.join(&self.separator)
+        };
+
+        let mut context = join(&rendered);
+        if let Some(max_tokens) = self.max_tokens {
+            while !rendered.is_empty() && count_tokens(&context, self.model) > max_tokens {
+                let lowest = rendered
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.1.total_cmp(&b.1))
+                    .map(|(index, _)| index)
+                    .expect("rendered isn't empty");
+                rendered.remove(lowest);
+                context = join(&rendered);
+            }
+        }
+        Ok(context)
+    }
+}
+
+/// Joins retrieved `results` into one block for a prompt, rendering each with
+/// `template`. See [`ContextFormat`] to change the separator or cap the tokens.
+pub fn format_context(results: &[(&str, f32)], template: &str) -> Result<String, TemplateError> {
+    ContextFormat::builder()
+        .template(template)
+        .build()
+        .format(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_context() {
+        let results = [
+            ("Cats purr when content.", 0.9),
+            ("Dogs wag their tails.", 0.5),
+            ("Birds sing at dawn.", 0.7),
+        ];
+
+        assert_eq!(
+            format_context(&results, "[{{rank}}] ({{score}}) {{text}}").unwrap(),
+            "[1] (0.90) Cats purr when content.\n\n\
+             [2] (0.50) Dogs wag their tails.\n\n\
+             [3] (0.70) Birds sing at dawn."
+        );
+
+        let format = ContextFormat::builder()
+            .separator("\n")
+            .max_tokens(14)
+            .build();
+        assert_eq!(
+            format.format(&results).unwrap(),
+            "Cats purr when content.\nBirds sing at dawn."
+        );
+
+        let format = ContextFormat::builder().max_tokens(1).build();
+        assert_eq!(format.format(&results).unwrap(), "");
+    }
+}
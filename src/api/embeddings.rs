@@ -1,14 +1,28 @@
+use futures::stream::{StreamExt, TryStreamExt};
 use ordered_float::NotNan;
-use reqwest::Client;
+use reqwest::{header::HeaderMap, Client};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::Range;
 use typed_builder::TypedBuilder;
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::client::{with_extra_headers, ApiConfig};
+use super::error::{parse_api_error, send_request};
+use super::hooks::{Call, Endpoint, Usage};
+use super::tokens::count_embedding_tokens;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum EmbeddingModel {
     #[serde(rename = "text-embedding-ada-002")]
     #[serde(alias = "text-embedding-ada-002-v2")]
     Ada,
+    #[serde(rename = "text-embedding-3-small")]
+    Small3,
+    #[serde(rename = "text-embedding-3-large")]
+    Large3,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,14 +32,78 @@ pub enum EmbeddingInput {
     Array(Vec<String>),
 }
 
+impl From<&str> for EmbeddingInput {
+    fn from(input: &str) -> Self {
+        Self::String(input.into())
+    }
+}
+
+impl From<String> for EmbeddingInput {
+    fn from(input: String) -> Self {
+        Self::String(input)
+    }
+}
+
+impl From<Vec<String>> for EmbeddingInput {
+    fn from(inputs: Vec<String>) -> Self {
+        Self::Array(inputs)
+    }
+}
+
+impl From<Vec<&str>> for EmbeddingInput {
+    fn from(inputs: Vec<&str>) -> Self {
+        inputs.into_iter().collect()
+    }
+}
+
+impl<const N: usize> From<[&str; N]> for EmbeddingInput {
+    fn from(inputs: [&str; N]) -> Self {
+        inputs.into_iter().collect()
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for EmbeddingInput {
+    fn from_iter<I: IntoIterator<Item = S>>(inputs: I) -> Self {
+        Self::Array(inputs.into_iter().map(Into::into).collect())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, TypedBuilder)]
 pub struct EmbeddingRequest {
     #[builder(default = EmbeddingModel::Ada)]
     pub model: EmbeddingModel,
+    #[builder(setter(transform = |input: impl Into<EmbeddingInput>| input.into()))]
     pub input: EmbeddingInput,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(default, setter(strip_option))]
     pub user: Option<String>,
+    /// Extra headers sent with the request, e.g. for routing through a gateway.
+    /// The built-in `Content-Type` and credential headers take precedence.
+    #[serde(skip)]
+    #[builder(default)]
+    pub headers: HeaderMap,
+}
+
+// `input` is required, so rather than mutators (which can't set a required
+// field), these wrap its setter and are only offered until it's set.
+impl<M, U, H> EmbeddingRequestBuilder<(M, (), U, H)> {
+    /// Sets the input to a single string.
+    pub fn input_str(
+        self,
+        input: impl Into<String>,
+    ) -> EmbeddingRequestBuilder<(M, (EmbeddingInput,), U, H)> {
+        self.input(EmbeddingInput::String(input.into()))
+    }
+
+    /// Sets the input to an array of strings, even if there's only one.
+    pub fn input_many(
+        self,
+        inputs: impl IntoIterator<Item = impl Into<String>>,
+    ) -> EmbeddingRequestBuilder<(M, (EmbeddingInput,), U, H)> {
+        self.input(EmbeddingInput::Array(
+            inputs.into_iter().map(Into::into).collect(),
+        ))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -35,7 +113,7 @@ pub struct EmbeddingItem {
     pub index: i32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EmbeddingUsage {
     pub prompt_tokens: u32,
     pub total_tokens: u32,
@@ -49,25 +127,46 @@ pub struct EmbeddingResponse {
     pub usage: EmbeddingUsage,
 }
 
+impl EmbeddingResponse {
+    /// The embeddings in input order.
+    pub fn embeddings(mut self) -> Vec<Vec<f32>> {
+        self.data.sort_by_key(|item| item.index);
+        self.data.into_iter().map(|i| i.embedding).collect()
+    }
+}
+
 impl EmbeddingRequest {
     pub async fn request(
         self,
         client: &Client,
         api_key: &str,
     ) -> Result<EmbeddingResponse, Box<dyn std::error::Error + Send + Sync>> {
-        let response = client
-            .post("https://api.openai.com/v1/embeddings")
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", api_key))
-            .json(&self)
-            .send()
-            .await?
-            .error_for_status()?;
+        self.request_config(&ApiConfig::new(client.clone(), api_key))
+            .await
+    }
+
+    /// Like [`request`](Self::request), but sent as `config` says, calling its hooks.
+    pub async fn request_config(
+        self,
+        config: &ApiConfig,
+    ) -> Result<EmbeddingResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let request = config.post("embeddings");
+        let mut call = Call::start(&config.hooks, Endpoint::Embeddings, self.model);
+        let (response, secrets) =
+            send_request(with_extra_headers(request, &self.headers).json(&self)).await?;
+
+        call.status = Some(response.status().as_u16());
+        if !response.status().is_success() {
+            return Err(parse_api_error(response, &secrets).await.into());
+        }
 
-        Ok(response.json::<EmbeddingResponse>().await?)
+        let response = response.json::<EmbeddingResponse>().await?;
+        call.usage = Some(Usage::Embeddings(response.usage));
+        Ok(response)
     }
 }
 
+/// Embeds `strings` in a single request, returning their embeddings in the same order.
 pub async fn string_embeddings(
     strings: impl Iterator<Item = impl Into<String>>,
     client: &Client,
@@ -77,9 +176,204 @@ pub async fn string_embeddings(
         .input(EmbeddingInput::Array(strings.map(|s| s.into()).collect()))
         .build();
 
-    let response = request.request(client, key).await?;
+    Ok(request.request(client, key).await?.embeddings())
+}
+
+/// Embeds the same `strings` with each of `models`, one request per model, for
+/// comparing retrieval across models.
+pub async fn embed_with_models(
+    strings: impl Iterator<Item = impl Into<String>>,
+    models: &[EmbeddingModel],
+    client: &Client,
+    key: &str,
+) -> Result<HashMap<EmbeddingModel, Vec<Vec<f32>>>, Box<dyn std::error::Error + Send + Sync>> {
+    let strings: Vec<String> = strings.map(|s| s.into()).collect();
+    embed_models(&strings, models, |model, strings| async move {
+        let request = EmbeddingRequest::builder()
+            .model(model)
+            .input(EmbeddingInput::Array(strings))
+            .build();
+        Ok(request.request(client, key).await?.embeddings())
+    })
+    .await
+}
+
+/// Embeds `strings` once per model with `embed`, which returns one embedding
+/// per string.
+async fn embed_models<F, Fut>(
+    strings: &[String],
+    models: &[EmbeddingModel],
+    embed: F,
+) -> Result<HashMap<EmbeddingModel, Vec<Vec<f32>>>, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: Fn(EmbeddingModel, Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let embed = &embed;
+    let requests = models.iter().map(|model| async move {
+        let embeddings = embed(*model, strings.to_vec()).await?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>((*model, embeddings))
+    });
+
+    Ok(futures::future::try_join_all(requests)
+        .await?
+        .into_iter()
+        .collect())
+}
+
+/// How [`string_embeddings_opts`] splits its input into requests.
+#[derive(Debug, Clone, Copy, TypedBuilder)]
+pub struct EmbeddingBatchOptions {
+    /// Maximum number of strings sent in one request.
+    #[builder(default = 2048)]
+    pub max_batch: usize,
+    /// Maximum number of requests in flight at once; `1` sends batches sequentially.
+    #[builder(default = 1)]
+    pub concurrency: usize,
+    /// Return embeddings in input order even when batches complete out of order.
+    /// Otherwise, batches are returned in completion order.
+    #[builder(default = true)]
+    pub preserve_order: bool,
+    /// Maximum number of tokens sent in one request, if batches should also be
+    /// packed by token count. A string over the budget is sent on its own.
+    #[builder(default, setter(strip_option))]
+    pub max_batch_tokens: Option<usize>,
+}
+
+/// The most tokens OpenAI's embedding models accept in a single input.
+pub const MAX_EMBEDDING_INPUT_TOKENS: usize = 8191;
+
+/// A string too long to embed, found while batching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbeddingInputTooLong {
+    pub index: usize,
+    pub tokens: usize,
+}
+
+impl std::fmt::Display for EmbeddingInputTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "input {} has {} tokens, over the limit of {MAX_EMBEDDING_INPUT_TOKENS}",
+            self.index, self.tokens
+        )
+    }
+}
+
+impl std::error::Error for EmbeddingInputTooLong {}
+
+/// Splits `strings` into consecutive batches of at most `max_batch` strings and,
+/// if set, at most `max_batch_tokens` tokens.
+pub fn batch_ranges(
+    strings: &[String],
+    options: &EmbeddingBatchOptions,
+) -> Result<Vec<Range<usize>>, EmbeddingInputTooLong> {
+    let max_batch = options.max_batch.max(1);
+    let Some(max_tokens) = options.max_batch_tokens else {
+        return Ok((0..strings.len())
+            .step_by(max_batch)
+            .map(|start| start..(start + max_batch).min(strings.len()))
+            .collect());
+    };
+
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut batch_tokens = 0;
+    for (index, string) in strings.iter().enumerate() {
+        let tokens = count_embedding_tokens(string);
+        if tokens > MAX_EMBEDDING_INPUT_TOKENS {
+            return Err(EmbeddingInputTooLong { index, tokens });
+        }
+
+        if index > start && (batch_tokens + tokens > max_tokens || index - start == max_batch) {
+            ranges.push(start..index);
+            start = index;
+            batch_tokens = 0;
+        }
+        batch_tokens += tokens;
+    }
+    if start < strings.len() {
+        ranges.push(start..strings.len());
+    }
+    Ok(ranges)
+}
+
+impl Default for EmbeddingBatchOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Like [`string_embeddings`], but splits the input into batches according to `options`.
+pub async fn string_embeddings_opts(
+    strings: impl Iterator<Item = impl Into<String>>,
+    client: &Client,
+    key: &str,
+    options: EmbeddingBatchOptions,
+) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+    string_embeddings_with_progress(strings, client, key, options, |_, _| {}).await
+}
+
+/// Like [`string_embeddings_opts`], but calls `progress(done, total)` with the
+/// number of strings embedded so far after each batch completes.
+pub async fn string_embeddings_with_progress(
+    strings: impl Iterator<Item = impl Into<String>>,
+    client: &Client,
+    key: &str,
+    options: EmbeddingBatchOptions,
+    progress: impl FnMut(usize, usize),
+) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+    let strings: Vec<String> = strings.map(|s| s.into()).collect();
+    embed_batches(&strings, &options, progress, |batch| async move {
+        let request = EmbeddingRequest::builder()
+            .input(EmbeddingInput::Array(batch))
+            .build();
+        Ok(request.request(client, key).await?.embeddings())
+    })
+    .await
+}
+
+/// Splits `strings` into batches per `options` and embeds each with `embed`,
+/// which returns one embedding per string in its batch.
+async fn embed_batches<F, Fut>(
+    strings: &[String],
+    options: &EmbeddingBatchOptions,
+    mut progress: impl FnMut(usize, usize),
+    embed: F,
+) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let total = strings.len();
+    let ranges = batch_ranges(strings, options)?;
+    let embed = &embed;
+    let batches = ranges
+        .into_iter()
+        .enumerate()
+        .map(|(batch_index, range)| async move {
+            let embeddings = embed(strings[range].to_vec()).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>((batch_index, embeddings))
+        });
+
+    let mut completed =
+        std::pin::pin!(futures::stream::iter(batches).buffer_unordered(options.concurrency.max(1)));
+    let mut results = Vec::new();
+    let mut done = 0;
+    while let Some(batch) = completed.try_next().await? {
+        done += batch.1.len();
+        progress(done, total);
+        results.push(batch);
+    }
+
+    if options.preserve_order {
+        results.sort_by_key(|(batch_index, _)| *batch_index);
+    }
 
-    Ok(response.data.into_iter().map(|i| i.embedding).collect())
+    Ok(results
+        .into_iter()
+        .flat_map(|(_, embeddings)| embeddings)
+        .collect())
 }
 
 // fn dot_product_fixed<T, const LEN: usize>(a: &[T; LEN], b: &[T; LEN]) -> T
@@ -93,11 +387,156 @@ pub async fn string_embeddings(
 //     sum
 // }
 
-fn dot_product<T>(a: &[T], b: &[T]) -> T
-where
-    T: std::ops::Mul<Output = T> + std::iter::Sum + Copy,
-{
-    a.iter().zip(b.iter()).map(|(a, b)| *a * *b).sum()
+/// The dot product of `a` and `b`, ignoring the excess of the longer one.
+///
+/// With the `simd` feature this sums eight lanes at a time, which is several
+/// times faster for embedding-sized vectors but can differ from the scalar sum
+/// in the last few bits.
+pub fn dot_product(a: &[f32], b: &[f32]) -> f32 {
+    #[cfg(feature = "simd")]
+    {
+        dot_product_simd(a, b)
+    }
+    #[cfg(not(feature = "simd"))]
+    {
+        a.iter().zip(b.iter()).map(|(a, b)| *a * *b).sum()
+    }
+}
+
+#[cfg(feature = "simd")]
+fn dot_product_simd(a: &[f32], b: &[f32]) -> f32 {
+    use wide::f32x8;
+
+    let len = a.len().min(b.len());
+    let (a, b) = (&a[..len], &b[..len]);
+    let (a_chunks, b_chunks) = (a.chunks_exact(8), b.chunks_exact(8));
+    let tail: f32 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(a, b)| a * b)
+        .sum();
+
+    let lanes = |chunk: &[f32]| f32x8::from(<[f32; 8]>::try_from(chunk).unwrap());
+    let sum = a_chunks
+        .zip(b_chunks)
+        .fold(f32x8::ZERO, |sum, (a, b)| lanes(a).mul_add(lanes(b), sum));
+    sum.reduce_add() + tail
+}
+
+pub fn norm(a: &[f32]) -> f32 {
+    dot_product(a, a).sqrt()
+}
+
+/// Scales `a` to unit length in place. Zero vectors are left untouched.
+pub fn normalize(a: &mut [f32]) {
+    let norm = norm(a);
+    if norm > 0. {
+        a.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Cosine similarity of `a` and `b`, or `0.0` if either has zero norm.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let denominator = norm(a) * norm(b);
+    if denominator > 0. {
+        dot_product(a, b) / denominator
+    } else {
+        0.
+    }
+}
+
+/// Computes the dot product of two unit-length vectors, returning `None` as soon as
+/// the result can no longer reach `threshold`.
+///
+/// The dimensions not yet summed are bounded by Cauchy-Schwarz using the norm left
+/// over from unit length, so the pruning is only valid for unit vectors such as
+/// OpenAI's embeddings.
+pub fn dot_product_at_least(a: &[f32], b: &[f32], threshold: f32) -> Option<f32> {
+    // Checking the bound costs a square root, so only do it every so often.
+    const CHECK_EVERY: usize = 64;
+    // Slack for rounding in vectors that are only approximately unit length.
+    const TOLERANCE: f32 = 1e-4;
+
+    let mut dot = 0.;
+    let mut a_norm_sq = 0.;
+    let mut b_norm_sq = 0.;
+    for (a, b) in a.chunks(CHECK_EVERY).zip(b.chunks(CHECK_EVERY)) {
+        for (a, b) in a.iter().zip(b) {
+            dot += a * b;
+            a_norm_sq += a * a;
+            b_norm_sq += b * b;
+        }
+
+        let remaining = ((1. - a_norm_sq).max(0.) * (1. - b_norm_sq).max(0.)).sqrt();
+        if dot + remaining + TOLERANCE < threshold {
+            return None;
+        }
+    }
+
+    (dot >= threshold).then_some(dot)
+}
+
+/// A NaN or infinite value found by [`sanitize_embedding`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NonFiniteEmbedding {
+    pub index: usize,
+    pub value: f32,
+}
+
+impl std::fmt::Display for NonFiniteEmbedding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "embedding has non-finite value {} at index {}",
+            self.value, self.index
+        )
+    }
+}
+
+impl std::error::Error for NonFiniteEmbedding {}
+
+/// Checks that every value in `embedding` is finite, e.g. before trusting vectors
+/// loaded from a cache.
+pub fn sanitize_embedding(embedding: &[f32]) -> Result<(), NonFiniteEmbedding> {
+    match embedding.iter().position(|x| !x.is_finite()) {
+        Some(index) => Err(NonFiniteEmbedding {
+            index,
+            value: embedding[index],
+        }),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SimilarityMetric {
+    /// Raw dot product. Equivalent to cosine similarity for unit-length vectors
+    /// such as OpenAI's embeddings.
+    #[default]
+    DotProduct,
+    Cosine,
+}
+
+impl SimilarityMetric {
+    pub fn similarity(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Self::DotProduct => dot_product(a, b),
+            Self::Cosine => cosine_similarity(a, b),
+        }
+    }
+
+    /// Prepares a vector so that a plain dot product yields this metric's similarity,
+    /// borrowing it when it needs no normalizing.
+    fn prepare<'a>(&self, a: &'a [f32]) -> Cow<'a, [f32]> {
+        match self {
+            Self::DotProduct => Cow::Borrowed(a),
+            Self::Cosine => {
+                let mut a = a.to_vec();
+                normalize(&mut a);
+                Cow::Owned(a)
+            }
+        }
+    }
 }
 
 pub trait Embedding {
@@ -122,20 +561,25 @@ impl Embedding for &[f32] {
     }
 }
 
+/// An item paired with its similarity to a query.
+///
+/// Orders by similarity, then by `index` so that among equal similarities the
+/// item seen first ranks higher.
 pub struct EmbeddingDistance<T> {
     item: T,
     distance: NotNan<f32>,
+    index: usize,
 }
 
 impl<T> PartialEq for EmbeddingDistance<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.distance == other.distance
+        self.distance == other.distance && self.index == other.index
     }
 }
 
 impl<T> PartialOrd for EmbeddingDistance<T> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.distance.cmp(&other.distance))
+        Some(self.cmp(other))
     }
 }
 
@@ -143,10 +587,16 @@ impl<T> Eq for EmbeddingDistance<T> {}
 
 impl<T> Ord for EmbeddingDistance<T> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.distance.cmp(&other.distance)
+        self.distance
+            .cmp(&other.distance)
+            .then_with(|| other.index.cmp(&self.index))
     }
 }
 
+/// Returns the `k` items most similar to `query`, most similar first.
+///
+/// Items with equal similarity keep the order they had in `content`, so results
+/// are deterministic.
 pub fn knn_search<'a, T, U>(
     query: &T,
     content: impl Iterator<Item = &'a U>,
@@ -156,19 +606,106 @@ where
     T: Embedding,
     U: Embedding,
 {
+    top_k(
+        content.map(|item| (item, dot_product(query.embedding(), item.embedding()))),
+        k,
+    )
+}
+
+/// Like [`knn_search`], but clones the matched items so the results don't borrow
+/// from `content`, e.g. to hold onto them across an `.await`. Prefer
+/// [`knn_search`] when items are expensive to clone.
+pub fn knn_search_owned<'a, T, U>(
+    query: &T,
+    content: impl Iterator<Item = &'a U>,
+    k: usize,
+) -> Vec<(U, f32)>
+where
+    T: Embedding,
+    U: Embedding + Clone + 'a,
+{
+    knn_search(query, content, k)
+        .into_iter()
+        .map(|(item, similarity)| (item.clone(), similarity))
+        .collect()
+}
+
+/// Like [`knn_search`], but ranks items by their similarity multiplied by
+/// `weight(item)`, e.g. a recency factor. The returned scores are the weighted ones.
+pub fn knn_search_weighted<'a, T, U>(
+    query: &T,
+    content: impl Iterator<Item = &'a U>,
+    k: usize,
+    weight: impl Fn(&U) -> f32,
+) -> Vec<(&'a U, f32)>
+where
+    T: Embedding,
+    U: Embedding + 'a,
+{
+    top_k(
+        content.map(|item| {
+            let similarity = dot_product(query.embedding(), item.embedding());
+            (item, similarity * weight(item))
+        }),
+        k,
+    )
+}
+
+/// Like [`knn_search`], but scores each item with `score(query, item)` instead of
+/// the dot product, e.g. for weighted dimensions or a learned metric.
+///
+/// `score` must be a similarity, where higher means closer. To rank by a distance,
+/// negate it (and the returned scores).
+pub fn knn_search_by<'a, T, U>(
+    query: &T,
+    content: impl Iterator<Item = &'a U>,
+    k: usize,
+    score: impl Fn(&[f32], &[f32]) -> f32,
+) -> Vec<(&'a U, f32)>
+where
+    T: Embedding,
+    U: Embedding + 'a,
+{
+    top_k(
+        content.map(|item| (item, score(query.embedding(), item.embedding()))),
+        k,
+    )
+}
+
+/// Merges the results of searching several shards (e.g. [`knn_search`] run on each)
+/// into the overall top `k`. Ties are broken by shard order, then by rank within
+/// the shard.
+pub fn knn_merge<T>(results: impl IntoIterator<Item = Vec<(T, f32)>>, k: usize) -> Vec<(T, f32)> {
+    top_k(results.into_iter().flatten(), k)
+}
+
+/// Keeps the `k` highest-scoring items, highest first, breaking ties by input order.
+///
+/// Items scoring NaN (from an embedding containing NaN) are skipped with a warning.
+pub(crate) fn top_k<T>(scored: impl Iterator<Item = (T, f32)>, k: usize) -> Vec<(T, f32)> {
+    if k == 0 {
+        return Vec::new();
+    }
+
     let mut heap = std::collections::BinaryHeap::with_capacity(k);
-    for item in content {
-        let distance = dot_product(query.embedding(), item.embedding());
+    for (index, (item, distance)) in scored.enumerate() {
+        let Ok(distance) = NotNan::new(distance) else {
+            log::warn!("skipping item {index}, whose similarity is NaN");
+            continue;
+        };
+
         if heap.len() < k {
             heap.push(Reverse(EmbeddingDistance {
                 item,
-                distance: NotNan::new(distance).unwrap(),
+                distance,
+                index,
             }));
-        } else if heap.peek().unwrap().0.distance.into_inner() < distance {
+        } else if heap.peek().unwrap().0.distance < distance {
             heap.pop();
             heap.push(Reverse(EmbeddingDistance {
                 item,
-                distance: NotNan::new(distance).unwrap(),
+                distance,
+                index,
             }));
         }
     }
@@ -178,9 +715,559 @@ where
         .collect()
 }
 
+/// How [`multi_query_search`] combines an item's similarities to each query.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Reduction {
+    #[default]
+    Max,
+    Mean,
+}
+
+/// Returns the `k` items most similar to the set of `queries`, scoring each item
+/// by reducing its similarity to every query.
+pub fn multi_query_search<'a, T, U>(
+    queries: &[T],
+    content: impl Iterator<Item = &'a U>,
+    k: usize,
+    metric: SimilarityMetric,
+    reduction: Reduction,
+) -> Vec<(&'a U, f32)>
+where
+    T: Embedding,
+    U: Embedding,
+{
+    if queries.is_empty() {
+        return Vec::new();
+    }
+
+    let queries: Vec<_> = queries
+        .iter()
+        .map(|q| metric.prepare(q.embedding()))
+        .collect();
+
+    let scored = content.map(|item| {
+        let embedding = item.embedding();
+        // Rather than normalizing a copy of every item, scale its dot products.
+        let scale = match metric {
+            SimilarityMetric::Cosine if norm(embedding) > 0. => 1. / norm(embedding),
+            _ => 1.,
+        };
+        let similarities = queries.iter().map(|q| dot_product(q, embedding) * scale);
+        let score = match reduction {
+            Reduction::Max => similarities.fold(f32::NEG_INFINITY, f32::max),
+            Reduction::Mean => similarities.sum::<f32>() / queries.len() as f32,
+        };
+        (item, score)
+    });
+
+    top_k(scored, k)
+}
+
+/// Returns every item whose similarity to `query` is at least `min_similarity`,
+/// sorted from most to least similar.
+pub fn threshold_search<'a, T, U>(
+    query: &T,
+    content: impl Iterator<Item = &'a U>,
+    min_similarity: f32,
+    metric: SimilarityMetric,
+) -> Vec<(&'a U, f32)>
+where
+    T: Embedding,
+    U: Embedding,
+{
+    let mut results: Vec<_> = content
+        .filter_map(|item| {
+            let similarity = metric.similarity(query.embedding(), item.embedding());
+            (similarity >= min_similarity).then_some((item, similarity))
+        })
+        .collect();
+    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+    results
+}
+
+/// Averages the embeddings sharing each label.
+pub fn centroids<L, U>(items: &[(L, U)]) -> HashMap<L, Vec<f32>>
+where
+    L: Eq + std::hash::Hash + Clone,
+    U: Embedding,
+{
+    let mut sums: HashMap<L, (Vec<f32>, usize)> = HashMap::new();
+    for (label, item) in items {
+        let embedding = item.embedding();
+        let (sum, count) = sums
+            .entry(label.clone())
+            .or_insert_with(|| (vec![0.; embedding.len()], 0));
+        sum.iter_mut().zip(embedding).for_each(|(s, x)| *s += x);
+        *count += 1;
+    }
+
+    sums.into_iter()
+        .map(|(label, (mut sum, count))| {
+            sum.iter_mut().for_each(|s| *s /= count as f32);
+            (label, sum)
+        })
+        .collect()
+}
+
+/// Returns the label of the centroid most similar to `query`, or `None` if there
+/// are no centroids. Ties go to the smallest label, so the result doesn't depend
+/// on the map's iteration order.
+///
+/// Centroids aren't unit length even when their members are, so
+/// [`SimilarityMetric::Cosine`] is usually the right choice here.
+pub fn classify<'a, T, L>(
+    query: &T,
+    centroids: &'a HashMap<L, Vec<f32>>,
+    metric: SimilarityMetric,
+) -> Option<&'a L>
+where
+    T: Embedding,
+    L: Ord,
+{
+    centroids
+        .iter()
+        .map(|(label, centroid)| (label, metric.similarity(query.embedding(), centroid)))
+        .filter(|(_, similarity)| !similarity.is_nan())
+        .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+        .map(|(label, _)| label)
+}
+
+/// Computes the full, symmetric matrix of pairwise similarities between `items`.
+pub fn pairwise_similarity<U: Embedding>(items: &[U], metric: SimilarityMetric) -> Vec<Vec<f32>> {
+    let upper = pairwise_similarity_upper(items, metric);
+
+    let mut matrix = vec![vec![0.; items.len()]; items.len()];
+    for (i, row) in upper.into_iter().enumerate() {
+        matrix[i][i] = metric.similarity(items[i].embedding(), items[i].embedding());
+        for (offset, similarity) in row.into_iter().enumerate() {
+            let j = i + 1 + offset;
+            matrix[i][j] = similarity;
+            matrix[j][i] = similarity;
+        }
+    }
+    matrix
+}
+
+/// Computes only the strict upper triangle of the pairwise similarity matrix.
+///
+/// Row `i` holds the similarities between item `i` and items `i + 1..`, so the
+/// last row is always empty.
+pub fn pairwise_similarity_upper<U: Embedding>(
+    items: &[U],
+    metric: SimilarityMetric,
+) -> Vec<Vec<f32>> {
+    let prepared: Vec<_> = items
+        .iter()
+        .map(|i| metric.prepare(i.embedding()))
+        .collect();
+
+    prepared
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            prepared[i + 1..]
+                .iter()
+                .map(|b| dot_product(a, b))
+                .collect()
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
-    // use super::*;
+    use super::*;
+
+    #[test]
+    fn test_embedding_input() {
+        let input =
+            |request: EmbeddingRequest| serde_json::to_value(request).unwrap()["input"].clone();
+
+        let request = EmbeddingRequest::builder().input("a").build();
+        assert_eq!(input(request), serde_json::json!("a"));
+        let request = EmbeddingRequest::builder().input(["a", "b"]).build();
+        assert_eq!(input(request), serde_json::json!(["a", "b"]));
+        let request = EmbeddingRequest::builder()
+            .input((1..=2).map(|i| i.to_string()).collect::<EmbeddingInput>())
+            .build();
+        assert_eq!(input(request), serde_json::json!(["1", "2"]));
+        let request = EmbeddingRequest::builder()
+            .input(vec!["c".to_string()])
+            .build();
+        assert_eq!(input(request), serde_json::json!(["c"]));
+
+        let request = EmbeddingRequest::builder()
+            .model(EmbeddingModel::Small3)
+            .input_str("a")
+            .build();
+        assert_eq!(input(request), serde_json::json!("a"));
+        let request = EmbeddingRequest::builder()
+            .input_many(["a"])
+            .user("me".into())
+            .build();
+        assert_eq!(input(request), serde_json::json!(["a"]));
+    }
+
+    #[test]
+    fn test_knn_search_ties() {
+        let query = vec![1., 0.];
+        let items = [
+            vec![0.5, 0.],
+            vec![1., 0.],
+            vec![0.5, 1.],
+            vec![1., 2.],
+            vec![0.5, 2.],
+        ];
+
+        let results = knn_search(&query, items.iter(), 4);
+        let results: Vec<_> = results.into_iter().map(|(item, _)| item).collect();
+        assert_eq!(results, vec![&items[1], &items[3], &items[0], &items[2]]);
+    }
+
+    #[test]
+    fn test_knn_search_owned() {
+        let query = vec![1., 0.];
+        let results = {
+            let items = [vec![0., 1.], vec![1., 0.]];
+            knn_search_owned(&query, items.iter(), 1)
+        };
+        assert_eq!(results, vec![(vec![1., 0.], 1.)]);
+    }
+
+    #[test]
+    fn test_knn_merge() {
+        let query = vec![1., 0.];
+        let items = [vec![1., 0.], vec![0.8, 0.6], vec![0., 1.], vec![0.6, 0.8]];
+        let (first, second) = items.split_at(2);
+
+        let shards = [first, second].map(|shard| knn_search(&query, shard.iter(), 2));
+        assert_eq!(knn_merge(shards, 3), knn_search(&query, items.iter(), 3));
+    }
+
+    #[test]
+    fn test_knn_search_weighted() {
+        let query = vec![1., 0.];
+        let items = [vec![1., 0.], vec![0.8, 0.6], vec![0., 1.]];
+
+        // Favor items by their second dimension, standing in for recency.
+        let results = knn_search_weighted(&query, items.iter(), 2, |item| 1. + item[1]);
+        assert_eq!(results[0].0, &items[1]);
+        assert!((results[0].1 - 1.28).abs() < 1e-6);
+        assert_eq!(results[1], (&items[0], 1.));
+    }
+
+    #[test]
+    fn test_knn_search_by() {
+        let query = vec![1., 1.];
+        let items = [vec![1., 5.], vec![2., 1.], vec![0., 1.]];
+
+        // Negated Manhattan distance on the first dimension only.
+        let results = knn_search_by(&query, items.iter(), 2, |a, b| -(a[0] - b[0]).abs());
+        assert_eq!(results, vec![(&items[0], 0.), (&items[1], -1.)]);
+    }
+
+    #[test]
+    fn test_non_finite() {
+        let query = vec![1., 0.];
+        let items = [vec![f32::NAN, 0.], vec![0.5, 0.5], vec![1., 0.]];
+
+        let results = knn_search(&query, items.iter(), 3);
+        assert_eq!(results, vec![(&items[2], 1.), (&items[1], 0.5)]);
+        assert!(knn_search(&query, items.iter(), 0).is_empty());
+
+        assert_eq!(
+            sanitize_embedding(&[0., f32::INFINITY]),
+            Err(NonFiniteEmbedding {
+                index: 1,
+                value: f32::INFINITY
+            })
+        );
+        assert!(sanitize_embedding(&items[1]).is_ok());
+    }
+
+    #[test]
+    fn test_batch_ranges() {
+        let strings: Vec<String> = ["one", "two three", &"word ".repeat(50), "four", "five"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let options = EmbeddingBatchOptions::builder().max_batch(2).build();
+        assert_eq!(
+            batch_ranges(&strings, &options).unwrap(),
+            vec![0..2, 2..4, 4..5]
+        );
+
+        let options = EmbeddingBatchOptions::builder()
+            .max_batch_tokens(10)
+            .build();
+        assert_eq!(
+            batch_ranges(&strings, &options).unwrap(),
+            vec![0..2, 2..3, 3..5]
+        );
+
+        let strings = vec!["word ".repeat(MAX_EMBEDDING_INPUT_TOKENS + 1)];
+        assert_eq!(batch_ranges(&strings, &options).unwrap_err().index, 0);
+    }
+
+    /// Embeds each string, a number, as a one-element vector after sleeping for
+    /// that many milliseconds times ten, tracking how many batches are in flight.
+    async fn embed_numbers(
+        batch: Vec<String>,
+        in_flight: &std::sync::atomic::AtomicUsize,
+        max_in_flight: &std::sync::atomic::AtomicUsize,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+        use std::sync::atomic::Ordering;
+
+        let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        max_in_flight.fetch_max(current, Ordering::SeqCst);
+        let numbers: Vec<f32> = batch.iter().map(|s| s.parse().unwrap()).collect();
+        let delay = numbers[0] as u64 * 10;
+        tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+        in_flight.fetch_sub(1, Ordering::SeqCst);
+        Ok(numbers.into_iter().map(|n| vec![n]).collect())
+    }
+
+    #[tokio::test]
+    async fn test_embed_batches() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // The first batch is the slowest, so batches complete in reverse order.
+        let strings: Vec<String> = ["5", "5", "3", "3", "1"].map(String::from).into();
+        let strings = &strings;
+        let embed = |options: EmbeddingBatchOptions| async move {
+            let (in_flight, max_in_flight) = (AtomicUsize::new(0), AtomicUsize::new(0));
+            let embeddings = embed_batches(
+                strings,
+                &options,
+                |_, _| {},
+                |batch| embed_numbers(batch, &in_flight, &max_in_flight),
+            )
+            .await
+            .unwrap();
+            let embeddings: Vec<f32> = embeddings.into_iter().map(|e| e[0]).collect();
+            (embeddings, max_in_flight.load(Ordering::SeqCst))
+        };
+
+        let options = EmbeddingBatchOptions::builder().max_batch(2).build();
+        assert_eq!(embed(options).await, (vec![5., 5., 3., 3., 1.], 1));
+
+        let options = EmbeddingBatchOptions::builder()
+            .max_batch(2)
+            .concurrency(3)
+            .build();
+        assert_eq!(embed(options).await, (vec![5., 5., 3., 3., 1.], 3));
+
+        let options = EmbeddingBatchOptions::builder()
+            .max_batch(2)
+            .concurrency(2)
+            .preserve_order(false)
+            .build();
+        assert_eq!(embed(options).await, (vec![3., 3., 1., 5., 5.], 2));
+    }
+
+    #[tokio::test]
+    async fn test_embed_batches_progress() {
+        use std::sync::atomic::AtomicUsize;
+
+        let strings: Vec<String> = ["5", "5", "3", "3", "1"].map(String::from).into();
+        let (in_flight, max_in_flight) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let options = EmbeddingBatchOptions::builder()
+            .max_batch(2)
+            .concurrency(3)
+            .preserve_order(false)
+            .build();
+
+        let mut calls = Vec::new();
+        embed_batches(
+            &strings,
+            &options,
+            |done, total| calls.push((done, total)),
+            |batch| embed_numbers(batch, &in_flight, &max_in_flight),
+        )
+        .await
+        .unwrap();
+
+        // One call per batch, counting strings in completion order.
+        assert_eq!(calls, vec![(1, 5), (3, 5), (5, 5)]);
+    }
+
+    #[tokio::test]
+    async fn test_embed_models() {
+        let strings: Vec<String> = ["1", "2", "3"].map(String::from).into();
+        let models = [EmbeddingModel::Large3, EmbeddingModel::Small3];
+
+        // Large3 answers last, and each model's embeddings are tagged with it.
+        let embeddings = embed_models(&strings, &models, |model, batch| async move {
+            let (scale, delay) = match model {
+                EmbeddingModel::Large3 => (100., 20),
+                _ => (10., 0),
+            };
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+            Ok(batch
+                .iter()
+                .map(|s| vec![scale * s.parse::<f32>().unwrap()])
+                .collect())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(
+            embeddings[&EmbeddingModel::Large3],
+            vec![vec![100.], vec![200.], vec![300.]]
+        );
+        assert_eq!(
+            embeddings[&EmbeddingModel::Small3],
+            vec![vec![10.], vec![20.], vec![30.]]
+        );
+
+        let error = embed_models(&strings, &models, |model, _| async move {
+            match model {
+                EmbeddingModel::Small3 => Err("rate limited".into()),
+                _ => Ok(Vec::new()),
+            }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "rate limited");
+    }
+
+    #[test]
+    fn test_embedding_response_order() {
+        let response: EmbeddingResponse = serde_json::from_value(serde_json::json!({
+            "object": "list",
+            "data": [
+                { "object": "embedding", "embedding": [2.0], "index": 1 },
+                { "object": "embedding", "embedding": [1.0], "index": 0 },
+            ],
+            "model": "text-embedding-3-small",
+            "usage": { "prompt_tokens": 2, "total_tokens": 2 },
+        }))
+        .unwrap();
+        assert_eq!(response.embeddings(), vec![vec![1.], vec![2.]]);
+    }
+
+    #[test]
+    fn test_classify() {
+        let items = [
+            ("greeting", vec![1., 0.]),
+            ("greeting", vec![0.8, 0.2]),
+            ("question", vec![0., 1.]),
+        ];
+
+        let centroids = centroids(&items);
+        assert_eq!(centroids["greeting"], vec![0.9, 0.1]);
+        assert_eq!(centroids["question"], vec![0., 1.]);
+
+        let query = vec![0.7, 0.3];
+        assert_eq!(
+            classify(&query, &centroids, SimilarityMetric::Cosine),
+            Some(&"greeting")
+        );
+        assert_eq!(
+            classify(&query, &HashMap::<&str, _>::new(), SimilarityMetric::Cosine),
+            None
+        );
+
+        let tied: HashMap<_, _> = ["d", "b", "a", "c"]
+            .into_iter()
+            .map(|label| (label, vec![1., 0.]))
+            .collect();
+        assert_eq!(
+            classify(&vec![1., 0.], &tied, SimilarityMetric::Cosine),
+            Some(&"a")
+        );
+    }
+
+    #[test]
+    fn test_multi_query_search() {
+        let queries = [vec![1., 0.], vec![0., 1.]];
+        let items = [vec![0.9, 0.], vec![0.6, 0.6], vec![-1., 0.]];
+
+        let search = |reduction| {
+            multi_query_search(
+                &queries,
+                items.iter(),
+                1,
+                SimilarityMetric::DotProduct,
+                reduction,
+            )
+        };
+        assert_eq!(search(Reduction::Max)[0].0, &items[0]);
+        assert_eq!(search(Reduction::Mean)[0].0, &items[1]);
+
+        let results = multi_query_search(
+            &queries,
+            items.iter(),
+            3,
+            SimilarityMetric::Cosine,
+            Reduction::Mean,
+        );
+        for (item, score) in results {
+            let expected = queries
+                .iter()
+                .map(|q| cosine_similarity(q, item))
+                .sum::<f32>()
+                / 2.;
+            assert!((score - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_dot_product() {
+        // An odd length exercises the SIMD path's remainder.
+        let a: Vec<f32> = (0..1539).map(|i| (i as f32).sin()).collect();
+        let b: Vec<f32> = (0..1539).map(|i| (i as f32 * 0.9).cos()).collect();
+        let expected: f64 = a.iter().zip(&b).map(|(a, b)| *a as f64 * *b as f64).sum();
+
+        assert!((dot_product(&a, &b) as f64 - expected).abs() < 1e-3);
+        assert_eq!(dot_product(&[1., 2., 3.], &[4., 5.]), 14.);
+    }
+
+    #[test]
+    fn test_dot_product_at_least() {
+        let mut a: Vec<f32> = (0..256).map(|i| (i as f32).sin()).collect();
+        let mut b: Vec<f32> = (0..256).map(|i| (i as f32 * 0.9).sin()).collect();
+        normalize(&mut a);
+        normalize(&mut b);
+        let dot = dot_product(&a, &b);
+
+        assert!((dot_product_at_least(&a, &b, dot - 0.01).unwrap() - dot).abs() < 1e-5);
+        assert_eq!(dot_product_at_least(&a, &b, dot + 0.01), None);
+
+        let opposite: Vec<f32> = a.iter().map(|x| -x).collect();
+        assert_eq!(dot_product_at_least(&a, &opposite, 0.5), None);
+    }
+
+    #[test]
+    fn test_threshold_search() {
+        let query = vec![1., 0.];
+        let items = [vec![0., 1.], vec![2., 0.], vec![0., 0.], vec![1., 1.]];
+
+        let results = threshold_search(&query, items.iter(), 0.5, SimilarityMetric::Cosine);
+        let results: Vec<_> = results.into_iter().map(|(item, _)| item).collect();
+        assert_eq!(results, vec![&items[1], &items[3]]);
+    }
+
+    #[test]
+    fn test_pairwise_similarity() {
+        let items = vec![vec![1., 0.], vec![0., 2.], vec![3., 3.]];
+
+        let upper = pairwise_similarity_upper(&items, SimilarityMetric::Cosine);
+        assert_eq!(upper.len(), 3);
+        assert_eq!(upper[0].len(), 2);
+        assert!(upper[2].is_empty());
+
+        let matrix = pairwise_similarity(&items, SimilarityMetric::Cosine);
+        for (i, row) in matrix.iter().enumerate() {
+            assert!((row[i] - 1.).abs() < 1e-6);
+            for (j, similarity) in row.iter().enumerate() {
+                assert_eq!(*similarity, matrix[j][i]);
+            }
+        }
+        assert!(matrix[0][1].abs() < 1e-6);
+        assert!((matrix[0][2] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+    }
 
     // #[tokio::test]
     // async fn test_simple_embeddings() {
@@ -0,0 +1,259 @@
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION, PROXY_AUTHORIZATION};
+use reqwest::{Request, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+
+/// The error OpenAI returns in the body of a failed request:
+/// `{ "error": { "message", "type", "param", "code" } }`.
+///
+/// Errors from [`parse_api_error`] are already [redacted](Self::redact), and
+/// have the credentials of the failed request masked, so the API key never
+/// shows up in their `Display` or `Debug` output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiError {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: Option<String>,
+    pub param: Option<String>,
+    pub code: Option<String>,
+    /// HTTP status of the failed response.
+    #[serde(skip)]
+    pub status: u16,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    error: ApiError,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OpenAI API error ({}): {}", self.status, self.message)?;
+        if let Some(code) = &self.code {
+            write!(f, " [{code}]")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ApiError {
+    /// Whether the request was rejected for the API key itself.
+    pub fn is_invalid_api_key(&self) -> bool {
+        self.status == 401 || self.code.as_deref() == Some("invalid_api_key")
+    }
+
+    /// Whether the account has run out of credit; retrying won't help.
+    pub fn is_insufficient_quota(&self) -> bool {
+        self.code.as_deref() == Some("insufficient_quota")
+    }
+
+    /// Whether the request was rate limited and may succeed if retried later.
+    pub fn is_rate_limited(&self) -> bool {
+        self.status == 429 && !self.is_insufficient_quota()
+    }
+
+    /// Masks anything that looks like an API key (`sk-...`) in the message, param
+    /// and code, as OpenAI echoes part of the key back when rejecting it and
+    /// proxies may echo all of it.
+    pub fn redact(mut self) -> Self {
+        self.message = redact_keys(&self.message);
+        self.param = self.param.as_deref().map(redact_keys);
+        self.code = self.code.as_deref().map(redact_keys);
+        self
+    }
+
+    /// Masks every occurrence of `secrets` in the message, param and code, for
+    /// keys that don't look like OpenAI's, such as Azure or gateway keys.
+    pub fn redact_secrets(mut self, secrets: &Secrets) -> Self {
+        self.message = secrets.redact(&self.message);
+        self.param = self.param.as_deref().map(|param| secrets.redact(param));
+        self.code = self.code.as_deref().map(|code| secrets.redact(code));
+        self
+    }
+}
+
+/// The credentials a request was sent with, so they can be masked in its errors.
+#[derive(Clone, Default)]
+pub struct Secrets(Vec<String>);
+
+impl std::fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secrets({})", self.0.len())
+    }
+}
+
+/// Shorter values are too likely to occur in an error message by chance.
+const MIN_SECRET_LEN: usize = 8;
+
+impl Secrets {
+    /// The values of `request`'s credential headers: `Authorization` (without
+    /// its scheme), headers marked sensitive, and headers whose names mention a
+    /// key, token, secret or auth, like Azure's `api-key`.
+    pub fn of(request: &Request) -> Self {
+        let secrets = request
+            .headers()
+            .iter()
+            .filter(|(name, value)| is_credential(name, value))
+            .filter_map(|(name, value)| {
+                let value = value.to_str().ok()?.trim();
+                if *name == AUTHORIZATION || *name == PROXY_AUTHORIZATION {
+                    Some(value.split_once(' ').map_or(value, |(_, v)| v.trim()))
+                } else {
+                    Some(value)
+                }
+            })
+            .filter(|value| value.len() >= MIN_SECRET_LEN)
+            .map(String::from)
+            .collect();
+        Self(secrets)
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.0
+            .iter()
+            .fold(text.to_string(), |text, secret| text.replace(secret, "***"))
+    }
+}
+
+fn is_credential(name: &HeaderName, value: &HeaderValue) -> bool {
+    let name = name.as_str();
+    value.is_sensitive()
+        || ["key", "token", "secret", "auth"]
+            .iter()
+            .any(|part| name.contains(part))
+}
+
+/// Sends `request`, returning the response along with the [`Secrets`] it
+/// carried for [`parse_api_error`].
+pub(crate) async fn send_request(
+    request: RequestBuilder,
+) -> Result<(Response, Secrets), reqwest::Error> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    let secrets = Secrets::of(&request);
+    Ok((client.execute(request).await?, secrets))
+}
+
+fn redact_keys(text: &str) -> String {
+    let is_key_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '*');
+
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("sk-") {
+        let at_word_start = !rest[..start].ends_with(|c: char| c.is_alphanumeric());
+        output.push_str(&rest[..start]);
+        let key = &rest[start + 3..];
+        let end = key.find(|c| !is_key_char(c)).unwrap_or(key.len());
+        if at_word_start && end > 0 {
+            output.push_str("sk-***");
+        } else {
+            output.push_str(&rest[start..start + 3 + end]);
+        }
+        rest = &key[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Reads a failed response's body into an [`ApiError`], masking `secrets` and
+/// anything else that looks like a key. Bodies that don't match OpenAI's error
+/// schema are kept verbatim as the message.
+pub async fn parse_api_error(response: Response, secrets: &Secrets) -> ApiError {
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap_or_default();
+
+    match serde_json::from_str::<ApiErrorBody>(&body) {
+        Ok(ApiErrorBody { error }) => ApiError { status, ..error },
+        Err(_) => ApiError {
+            message: body,
+            error_type: None,
+            param: None,
+            code: None,
+            status,
+        },
+    }
+    .redact_secrets(secrets)
+    .redact()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse_api_error() {
+        let response = http::Response::builder()
+            .status(401)
+            .body(
+                r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "param": null, "code": "invalid_api_key"}}"#,
+            )
+            .unwrap();
+
+        let error = parse_api_error(response.into(), &Secrets::default()).await;
+        assert_eq!(error.status, 401);
+        assert_eq!(error.code.as_deref(), Some("invalid_api_key"));
+        assert_eq!(error.error_type.as_deref(), Some("invalid_request_error"));
+        assert!(error.is_invalid_api_key() && !error.is_rate_limited());
+
+        let response = http::Response::builder()
+            .status(502)
+            .body("Bad gateway")
+            .unwrap();
+        assert_eq!(
+            parse_api_error(response.into(), &Secrets::default())
+                .await
+                .message,
+            "Bad gateway"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redact() {
+        let key = "sk-proj-abc123_XYZ";
+
+        let response = http::Response::builder()
+            .status(401)
+            .body(format!(
+                "Incorrect API key provided: {key}. A task-based sk- hint."
+            ))
+            .unwrap();
+        let error = parse_api_error(response.into(), &Secrets::default()).await;
+        assert_eq!(
+            error.message,
+            "Incorrect API key provided: sk-***. A task-based sk- hint."
+        );
+        assert!(!format!("{error} {error:?}").contains("abc123"));
+
+        // Azure and gateway keys don't look like OpenAI's, so the request's own
+        // credentials are masked wherever they're echoed.
+        let request = reqwest::Client::new()
+            .post("https://example.openai.azure.com/openai/deployments/gpt-4/chat/completions")
+            .header("api-key", "0123456789abcdef")
+            .header("x-gateway-token", "gateway-secret")
+            .bearer_auth(key)
+            .header("x-request-id", "request-12345")
+            .build()
+            .unwrap();
+        let secrets = Secrets::of(&request);
+        let response = http::Response::builder()
+            .status(401)
+            .body(
+                r#"{"error": {"message": "Key 0123456789abcdef is invalid for request-12345", "type": null, "param": "gateway-secret", "code": "bad_key_0123456789abcdef"}}"#,
+            )
+            .unwrap();
+        let error = parse_api_error(response.into(), &secrets).await;
+        assert_eq!(error.message, "Key *** is invalid for request-12345");
+        assert_eq!(error.param.as_deref(), Some("***"));
+        assert_eq!(error.code.as_deref(), Some("bad_key_***"));
+
+        // Transport errors mention the URL but never the headers.
+        let error = reqwest::Client::new()
+            .post("http://127.0.0.1:1/v1/chat/completions")
+            .bearer_auth(key)
+            .send()
+            .await
+            .unwrap_err();
+        assert!(!format!("{error} {error:?}").contains(key));
+    }
+}
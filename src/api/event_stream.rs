@@ -0,0 +1,229 @@
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use reqwest::RequestBuilder;
+
+use super::error::{parse_api_error, send_request};
+
+/// One server-sent event, holding its `data` lines and any comment lines.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SseFrame {
+    pub data: Option<String>,
+    pub comments: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// A single frame wasn't valid UTF-8; the frames around it are unaffected.
+    Utf8(std::str::Utf8Error),
+    Transport(reqwest::Error),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Utf8(e) => write!(f, "stream frame wasn't valid UTF-8: {e}"),
+            Self::Transport(e) => write!(f, "stream transport error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Splits a byte stream into server-sent event frames.
+///
+/// Frames are decoded individually, so a corrupt frame can be reported and
+/// skipped without losing the rest of the stream. Lines may end with `\n`, `\r\n`
+/// or a bare `\r`, as the SSE spec allows.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    /// The last frame ended on a `\r` at the end of the buffer, so a `\n` starting
+    /// the next chunk is the rest of that line ending.
+    skip_lf: bool,
+}
+
+type FrameResult = Result<SseFrame, std::str::Utf8Error>;
+
+impl FrameDecoder {
+    /// Feeds the next chunk of bytes, returning every frame it completes.
+    pub fn feed(&mut self, mut bytes: &[u8]) -> Vec<FrameResult> {
+        if self.skip_lf && !bytes.is_empty() {
+            self.skip_lf = false;
+            bytes = bytes.strip_prefix(b"\n").unwrap_or(bytes);
+        }
+        self.buffer.extend_from_slice(bytes);
+
+        let mut frames = Vec::new();
+        while let Some((end, next)) = frame_end(&self.buffer) {
+            self.skip_lf = next == self.buffer.len() && self.buffer[next - 1] == b'\r';
+            let frame: Vec<u8> = self.buffer.drain(..next).take(end).collect();
+            // A blank line with nothing before it dispatches nothing.
+            if !frame.is_empty() {
+                frames.push(decode_frame(&frame));
+            }
+        }
+        frames
+    }
+
+    /// Decodes what's left once the stream ends, for a last frame sent without
+    /// its closing blank line.
+    pub fn finish(&mut self) -> Option<FrameResult> {
+        let frame = std::mem::take(&mut self.buffer);
+        let is_blank = frame.iter().all(|b| matches!(b, b'\r' | b'\n'));
+        (!is_blank).then(|| decode_frame(&frame))
+    }
+}
+
+fn decode_frame(frame: &[u8]) -> FrameResult {
+    std::str::from_utf8(frame).map(parse_frame)
+}
+
+/// Finds the blank line ending the first frame, returning where the frame's
+/// content ends and where the next frame starts.
+///
+/// A `\r` at the end of the buffer ends a line, even though a `\n` completing it
+/// may be yet to arrive.
+fn frame_end(buffer: &[u8]) -> Option<(usize, usize)> {
+    let mut line_start = 0;
+    loop {
+        let offset = buffer[line_start..]
+            .iter()
+            .position(|b| matches!(b, b'\r' | b'\n'))?;
+        let terminator = line_start + offset;
+        let next = match &buffer[terminator..] {
+            [b'\r', b'\n', ..] => terminator + 2,
+            _ => terminator + 1,
+        };
+        if offset == 0 {
+            return Some((line_start, next));
+        }
+        line_start = next;
+    }
+}
+
+fn parse_frame(text: &str) -> SseFrame {
+    let mut frame = SseFrame::default();
+    for line in text.split(['\r', '\n']).filter(|line| !line.is_empty()) {
+        if let Some(comment) = line.strip_prefix(':') {
+            frame.comments.push(strip_space(comment).to_string());
+            continue;
+        }
+
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        if field == "data" {
+            let data = frame.data.get_or_insert_with(String::new);
+            if !data.is_empty() {
+                data.push('\n');
+            }
+            data.push_str(strip_space(value));
+        }
+    }
+    frame
+}
+
+fn strip_space(value: &str) -> &str {
+    value.strip_prefix(' ').unwrap_or(value)
+}
+
+/// An open event stream response.
+///
+/// Dropping it drops the underlying response, releasing the connection.
+pub struct EventConnection {
+    status: u16,
+    bytes: BoxStream<'static, reqwest::Result<Bytes>>,
+    decoder: FrameDecoder,
+    pending: std::collections::VecDeque<FrameResult>,
+}
+
+impl EventConnection {
+    /// Sends the request, failing with an [`ApiError`](super::error::ApiError) if
+    /// the response isn't successful.
+    pub async fn open(
+        request: RequestBuilder,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let (response, secrets) = send_request(request).await?;
+        if !response.status().is_success() {
+            return Err(parse_api_error(response, &secrets).await.into());
+        }
+
+        Ok(Self::from_response(response))
+    }
+
+    pub fn from_response(response: reqwest::Response) -> Self {
+        Self {
+            status: response.status().as_u16(),
+            bytes: response.bytes_stream().fuse().boxed(),
+            decoder: FrameDecoder::default(),
+            pending: Default::default(),
+        }
+    }
+
+    /// The HTTP status of the response.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub async fn next_frame(&mut self) -> Option<Result<SseFrame, FrameError>> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Some(frame.map_err(FrameError::Utf8));
+            }
+
+            match self.bytes.next().await {
+                Some(Ok(bytes)) => self.pending.extend(self.decoder.feed(&bytes)),
+                Some(Err(e)) => return Some(Err(FrameError::Transport(e))),
+                None => return self.decoder.finish().map(|f| f.map_err(FrameError::Utf8)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_decoder() {
+        let mut decoder = FrameDecoder::default();
+
+        assert!(decoder.feed(b"data: {\"a\"").is_empty());
+        let frames = decoder.feed(b": 1}\n\n: keepalive\n\ndata: one\r\ndata: two\r\n\r\n");
+        let frames: Vec<_> = frames.into_iter().map(Result::unwrap).collect();
+        assert_eq!(frames[0].data.as_deref(), Some("{\"a\": 1}"));
+        assert_eq!(frames[1].data, None);
+        assert_eq!(frames[1].comments, vec!["keepalive".to_string()]);
+        assert_eq!(frames[2].data.as_deref(), Some("one\ntwo"));
+
+        let frames = decoder.feed(b"data: \xff\xfe\n\ndata: [DONE]\n\n");
+        assert!(frames[0].is_err());
+        assert_eq!(frames[1].as_ref().unwrap().data.as_deref(), Some("[DONE]"));
+        assert!(decoder.finish().is_none());
+    }
+
+    #[test]
+    fn test_frame_decoder_line_endings() {
+        let data = |frames: Vec<FrameResult>| -> Vec<_> {
+            frames.into_iter().map(|f| f.unwrap().data).collect()
+        };
+
+        let mut decoder = FrameDecoder::default();
+        assert_eq!(
+            data(decoder.feed(b"data: one\rdata: two\r\rdata: three\r\n\r\n")),
+            vec![Some("one\ntwo".into()), Some("three".into())]
+        );
+
+        // A frame ending in a bare `\r`, or the first half of a `\r\n` split across chunks.
+        assert_eq!(data(decoder.feed(b"data: a\r\r")), vec![Some("a".into())]);
+        assert_eq!(data(decoder.feed(b"\ndata: b\r")), vec![]);
+        assert_eq!(data(decoder.feed(b"\n\r")), vec![Some("b".into())]);
+        assert_eq!(data(decoder.feed(b"\n: ping\n\n")).len(), 1);
+
+        // The stream may end without a final blank line.
+        assert!(decoder.feed(b"data: [DONE]\n").is_empty());
+        assert_eq!(
+            decoder.finish().unwrap().unwrap().data.as_deref(),
+            Some("[DONE]")
+        );
+        assert!(decoder.finish().is_none());
+    }
+}
@@ -0,0 +1,264 @@
+//! Callbacks around every API call sent with an [`ApiConfig`](super::client::ApiConfig)
+//! or [`AzureConfig`](super::azure::AzureConfig), for metrics and logging without
+//! wrapping each call site.
+//!
+//! ```ignore
+//! let config = ApiConfig::new(client, api_key).with_hooks(Hooks::new().on_response(|meta| {
+//!     metrics::histogram!("openai_latency", meta.latency);
+//! }));
+//! ```
+
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::chat::ChatUsage;
+use super::embeddings::EmbeddingUsage;
+use super::error::ApiError;
+
+/// Which API a call went to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Chat,
+    ChatStream,
+    Embeddings,
+}
+
+/// Passed to [`Hooks::on_request`] just before a request is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestMeta {
+    pub endpoint: Endpoint,
+    pub model: String,
+}
+
+/// Token usage reported by a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    Chat(ChatUsage),
+    Embeddings(EmbeddingUsage),
+}
+
+/// Passed to [`Hooks::on_response`] once a call finishes, successfully or not.
+///
+/// For streams the call finishes when the response headers arrive, so `latency`
+/// is the time to the first byte and `usage` is `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseMeta {
+    pub endpoint: Endpoint,
+    pub model: String,
+    pub latency: Duration,
+    /// The HTTP status, or `None` if no response was received.
+    pub status: Option<u16>,
+    pub usage: Option<Usage>,
+}
+
+type Hook<T> = Arc<dyn Fn(&T) + Send + Sync>;
+
+/// The callbacks set on an [`ApiConfig`](super::client::ApiConfig) or
+/// [`AzureConfig`](super::azure::AzureConfig).
+#[derive(Clone, Default)]
+pub struct Hooks {
+    on_request: Option<Hook<RequestMeta>>,
+    on_response: Option<Hook<ResponseMeta>>,
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_request", &self.on_request.is_some())
+            .field("on_response", &self.on_response.is_some())
+            .finish()
+    }
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_request(mut self, hook: impl Fn(&RequestMeta) + Send + Sync + 'static) -> Self {
+        self.on_request = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn on_response(mut self, hook: impl Fn(&ResponseMeta) + Send + Sync + 'static) -> Self {
+        self.on_response = Some(Arc::new(hook));
+        self
+    }
+}
+
+/// An API call in progress. Fires `on_request` when started and `on_response`
+/// when dropped, with whatever `status` and `usage` were recorded by then, so
+/// early returns are reported too.
+pub(crate) struct Call {
+    hooks: Hooks,
+    endpoint: Endpoint,
+    model: String,
+    started: Instant,
+    pub status: Option<u16>,
+    pub usage: Option<Usage>,
+}
+
+impl Call {
+    pub fn start(hooks: &Hooks, endpoint: Endpoint, model: impl Serialize) -> Self {
+        let hooks = hooks.clone();
+        let model = if hooks.on_request.is_some() || hooks.on_response.is_some() {
+            serde_json::to_value(model)
+                .ok()
+                .and_then(|model| model.as_str().map(str::to_string))
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        if let Some(on_request) = &hooks.on_request {
+            on_request(&RequestMeta {
+                endpoint,
+                model: model.clone(),
+            });
+        }
+
+        Self {
+            hooks,
+            endpoint,
+            model,
+            started: Instant::now(),
+            status: None,
+            usage: None,
+        }
+    }
+
+    /// Records the status of a failed call from its error, if it's an [`ApiError`].
+    pub fn record_error(&mut self, error: &(dyn std::error::Error + Send + Sync + 'static)) {
+        if let Some(error) = error.downcast_ref::<ApiError>() {
+            self.status = Some(error.status);
+        }
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        if let Some(on_response) = &self.hooks.on_response {
+            on_response(&ResponseMeta {
+                endpoint: self.endpoint,
+                model: std::mem::take(&mut self.model),
+                latency: self.started.elapsed(),
+                status: self.status,
+                usage: self.usage,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::chat::{ChatModel, ChatRequest};
+    use crate::api::client::ApiConfig;
+    use crate::api::embeddings::{EmbeddingModel, EmbeddingRequest};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let body = if buf[..n].starts_with(b"POST /v1/chat/completions ") {
+                    r#"{"id":"1","object":"chat.completion","created":0,"choices":[{"index":0,"message":{"role":"assistant","content":"Hi"},"finish_reason":"stop"}],"usage":{"prompt_tokens":3,"completion_tokens":1,"total_tokens":4}}"#
+                } else {
+                    r#"{"object":"list","data":[{"object":"embedding","embedding":[1.0],"index":0}],"model":"text-embedding-3-small","usage":{"prompt_tokens":2,"total_tokens":2}}"#
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let hooks = Hooks::new()
+            .on_request({
+                let requests = requests.clone();
+                move |meta| requests.lock().unwrap().push(meta.clone())
+            })
+            .on_response({
+                let responses = responses.clone();
+                move |meta| responses.lock().unwrap().push(meta.clone())
+            });
+        let config = ApiConfig::new(reqwest::Client::new(), "key")
+            .with_base_url(format!("http://{address}/v1"))
+            .with_hooks(hooks);
+
+        let request = || {
+            ChatRequest::builder()
+                .model(ChatModel::GPT3)
+                .user("Hi")
+                .build()
+        };
+        request().request_config(&config).await.unwrap();
+        let response = request().request_raw_config(&config).await.unwrap();
+        assert_eq!(response.status(), 200);
+        EmbeddingRequest::builder()
+            .model(EmbeddingModel::Small3)
+            .input("Hi")
+            .build()
+            .request_config(&config)
+            .await
+            .unwrap();
+
+        // Without hooks on the config, nothing is reported.
+        let config = ApiConfig::new(reqwest::Client::new(), "key")
+            .with_base_url(format!("http://{address}/v1"));
+        request().request_config(&config).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let expected = |endpoint, model: &str| RequestMeta {
+            endpoint,
+            model: model.into(),
+        };
+        assert_eq!(
+            *requests,
+            vec![
+                expected(Endpoint::Chat, "gpt-3.5-turbo-0613"),
+                expected(Endpoint::Chat, "gpt-3.5-turbo-0613"),
+                expected(Endpoint::Embeddings, "text-embedding-3-small"),
+            ]
+        );
+
+        let responses = responses.lock().unwrap();
+        let summary: Vec<_> = responses
+            .iter()
+            .map(|meta| (meta.endpoint, meta.status, meta.usage))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    Endpoint::Chat,
+                    Some(200),
+                    Some(Usage::Chat(ChatUsage {
+                        prompt_tokens: 3,
+                        completion_tokens: 1,
+                        total_tokens: 4,
+                    }))
+                ),
+                (Endpoint::Chat, Some(200), None),
+                (
+                    Endpoint::Embeddings,
+                    Some(200),
+                    Some(Usage::Embeddings(EmbeddingUsage {
+                        prompt_tokens: 2,
+                        total_tokens: 2,
+                    }))
+                ),
+            ]
+        );
+    }
+}
@@ -1,3 +1,22 @@
+#[cfg(feature = "ndarray")]
+pub mod array;
+pub mod azure;
+pub mod backend;
 pub mod chat;
+pub mod client;
+pub mod context;
 pub mod embeddings;
+pub mod error;
+mod event_stream;
+pub mod hooks;
+pub mod models;
+pub mod moderation;
 mod parsing;
+pub mod quantize;
+pub mod rate_limit;
+pub mod rechunk;
+pub mod session;
+pub mod store;
+pub mod template;
+pub mod tokens;
+pub mod transcript;
@@ -0,0 +1,60 @@
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use super::error::{parse_api_error, send_request};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub id: String,
+    pub owned_by: String,
+    pub created: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+/// Lists the models available to `api_key`.
+pub async fn list_models(
+    client: &Client,
+    api_key: &str,
+) -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send + Sync>> {
+    let request = client
+        .get("https://api.openai.com/v1/models")
+        .header("Authorization", format!("Bearer {}", api_key));
+    let (response, secrets) = send_request(request).await?;
+
+    if !response.status().is_success() {
+        return Err(parse_api_error(response, &secrets).await.into());
+    }
+
+    Ok(response.json::<ModelList>().await?.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_list() {
+        let list: ModelList = serde_json::from_str(
+            r#"{
+                "object": "list",
+                "data": [
+                    { "id": "gpt-4", "object": "model", "created": 1687882411, "owned_by": "openai" }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            list.data,
+            vec![ModelInfo {
+                id: "gpt-4".into(),
+                owned_by: "openai".into(),
+                created: 1687882411,
+            }]
+        );
+    }
+}
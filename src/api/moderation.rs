@@ -0,0 +1,99 @@
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use typed_builder::TypedBuilder;
+
+use super::error::{parse_api_error, send_request};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ModerationModel {
+    #[serde(rename = "text-moderation-latest")]
+    Latest,
+    #[serde(rename = "text-moderation-stable")]
+    Stable,
+    #[serde(rename = "omni-moderation-latest")]
+    OmniLatest,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ModerationInput {
+    String(String),
+    Array(Vec<String>),
+}
+
+#[derive(Debug, Serialize, Deserialize, TypedBuilder)]
+pub struct ModerationRequest {
+    pub input: ModerationInput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(default, setter(strip_option))]
+    pub model: Option<ModerationModel>,
+}
+
+/// The verdict for one input, keyed by category name (e.g. `"hate/threatening"`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    pub categories: BTreeMap<String, bool>,
+    pub category_scores: BTreeMap<String, f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModerationResponse {
+    pub id: String,
+    pub model: String,
+    pub results: Vec<ModerationResult>,
+}
+
+impl ModerationResponse {
+    /// Whether any of the inputs was flagged.
+    pub fn flagged(&self) -> bool {
+        self.results.iter().any(|r| r.flagged)
+    }
+}
+
+impl ModerationRequest {
+    pub async fn request(
+        self,
+        client: &Client,
+        api_key: &str,
+    ) -> Result<ModerationResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let request = client
+            .post("https://api.openai.com/v1/moderations")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", api_key))
+            .json(&self);
+        let (response, secrets) = send_request(request).await?;
+
+        if !response.status().is_success() {
+            return Err(parse_api_error(response, &secrets).await.into());
+        }
+
+        Ok(response.json::<ModerationResponse>().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moderation_response() {
+        let response: ModerationResponse = serde_json::from_str(
+            r#"{
+                "id": "modr-123",
+                "model": "text-moderation-007",
+                "results": [{
+                    "flagged": true,
+                    "categories": { "hate": false, "violence": true },
+                    "category_scores": { "hate": 0.01, "violence": 0.93 }
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(response.flagged());
+        assert!(response.results[0].categories["violence"]);
+        assert_eq!(response.results[0].category_scores["hate"], 0.01);
+    }
+}
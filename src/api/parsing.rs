@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 #[derive(Default, Clone)]
 pub enum JsonState {
     #[default]
@@ -5,6 +7,8 @@ pub enum JsonState {
     Active {
         data: String,
         num_brackets: usize,
+        /// Nesting depth of both `{` and `[`, checked against [`JsonLimits::max_depth`].
+        depth: usize,
         in_string: bool,
         escaped: bool,
     },
@@ -17,22 +21,161 @@ pub enum JsonState {
     },
 }
 
+#[cfg(feature = "trace-parser")]
+impl JsonState {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Idle => "Idle",
+            Self::Active { .. } => "Active",
+            Self::MaybeIgnore { .. } => "MaybeIgnore",
+            Self::Ignore { .. } => "Ignore",
+        }
+    }
+
+    fn depth(&self) -> usize {
+        match self {
+            Self::Active { depth, .. } => *depth,
+            _ => 0,
+        }
+    }
+}
+
+/// Logs a change of state, or of bracket depth within an object, caused by `ch`.
+#[cfg(feature = "trace-parser")]
+fn trace_transition(before: (&'static str, usize), after: &JsonState, ch: char, position: usize) {
+    let (from, from_depth) = before;
+    let (to, depth) = (after.name(), after.depth());
+    if from != to || from_depth != depth {
+        tracing::trace!(from, to, depth, ?ch, position, "JSON parser transition");
+    }
+}
+
+/// Bounds on a single streamed JSON object, guarding against runaway generations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JsonLimits {
+    /// Maximum nesting depth of objects and arrays.
+    pub max_depth: usize,
+    /// Maximum size of the accumulated object in bytes.
+    pub max_bytes: usize,
+}
+
+impl Default for JsonLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 128,
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Which of the [`JsonLimits`] was exceeded, and its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonLimitKind {
+    Depth(usize),
+    Bytes(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonLimitError {
+    pub kind: JsonLimitKind,
+    /// Prose from the failing chunk that came before the discarded object, which
+    /// would otherwise be lost with it.
+    pub prose: String,
+}
+
+impl std::fmt::Display for JsonLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            JsonLimitKind::Depth(max) => write!(f, "streamed JSON exceeded max depth of {max}"),
+            JsonLimitKind::Bytes(max) => {
+                write!(f, "streamed JSON exceeded max size of {max} bytes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for JsonLimitError {}
+
+/// A JSON object completed by the stream, with the character range it occupied
+/// within the whole stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletedJson {
+    pub json: String,
+    pub span: Range<usize>,
+}
+
+/// Where the parser is within the whole stream, in characters.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StreamPosition {
+    chars: usize,
+    json_start: usize,
+}
+
+/// Stateful wrapper around [`parse_json_from_stream`] that carries the parser
+/// state between stream deltas.
+#[derive(Default, Clone)]
+pub struct JsonStreamParser {
+    state: JsonState,
+    limits: JsonLimits,
+    position: StreamPosition,
+}
+
+impl JsonStreamParser {
+    pub fn with_limits(limits: JsonLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Feeds the next chunk of the stream, returning the JSON objects completed
+    /// by this chunk along with the prose that was not part of a JSON object.
+    ///
+    /// If the object being read exceeds the parser's limits, it is discarded and
+    /// the parser returns to the idle state. The chunk's prose up to that point is
+    /// returned in the error.
+    pub fn feed(&mut self, input: &str) -> Result<(Vec<CompletedJson>, String), JsonLimitError> {
+        let (state, json, filtered) = parse_json_from_stream(
+            input,
+            std::mem::take(&mut self.state),
+            &self.limits,
+            &mut self.position,
+        )?;
+        self.state = state;
+        Ok((json, filtered))
+    }
+}
+
 pub fn parse_json_from_stream(
     input: &str,
     mut json_state: JsonState,
-) -> (JsonState, Option<String>, String) {
-    let mut completed_json = None;
+    limits: &JsonLimits,
+    position: &mut StreamPosition,
+) -> Result<(JsonState, Vec<CompletedJson>, String), JsonLimitError> {
+    // Empty deltas are common in OpenAI's stream; there's nothing to transition on.
+    if input.is_empty() {
+        return Ok((json_state, Vec::new(), String::new()));
+    }
+
+    let mut completed_json = Vec::new();
     let mut filtered_delta = String::new();
 
     for ch in input.chars() {
+        #[cfg(feature = "trace-parser")]
+        let before = (json_state.name(), json_state.depth());
+
         json_state = match json_state {
             JsonState::Idle => match ch {
-                '{' => JsonState::Active {
-                    data: "{".to_string(),
-                    num_brackets: 1,
-                    in_string: false,
-                    escaped: false,
-                },
+                '{' => {
+                    position.json_start = position.chars;
+                    JsonState::Active {
+                        data: "{".to_string(),
+                        num_brackets: 1,
+                        depth: 1,
+                        in_string: false,
+                        escaped: false,
+                    }
+                }
                 '`' => {
                     filtered_delta.push(ch);
                     JsonState::MaybeIgnore { tick_count: 1 }
@@ -45,6 +188,7 @@ pub fn parse_json_from_stream(
             JsonState::Active {
                 mut data,
                 num_brackets,
+                depth,
                 in_string,
                 escaped,
             } => {
@@ -55,21 +199,53 @@ pub fn parse_json_from_stream(
                         JsonState::Active {
                             data,
                             num_brackets: num_brackets + 1,
+                            depth: depth + 1,
+                            in_string,
+                            escaped,
+                        }
+                    }
+                    '[' if !in_string => {
+                        data.push(ch);
+                        JsonState::Active {
+                            data,
+                            num_brackets,
+                            depth: depth + 1,
+                            in_string,
+                            escaped,
+                        }
+                    }
+                    ']' if !in_string => {
+                        data.push(ch);
+                        JsonState::Active {
+                            data,
+                            num_brackets,
+                            depth: depth.saturating_sub(1),
                             in_string,
                             escaped,
                         }
                     }
                     '}' if !in_string => {
                         let num_brackets = num_brackets - 1;
+                        let depth = depth.saturating_sub(1);
                         data.push(ch);
                         if num_brackets == 0 {
                             // We've finished reading the JSON object
-                            completed_json = Some(data);
+                            #[cfg(feature = "trace-parser")]
+                            tracing::debug!(
+                                span = ?(position.json_start..position.chars + 1),
+                                bytes = data.len(),
+                                "JSON parser completed an object"
+                            );
+                            completed_json.push(CompletedJson {
+                                json: data,
+                                span: position.json_start..position.chars + 1,
+                            });
                             JsonState::Idle
                         } else {
                             JsonState::Active {
                                 data,
                                 num_brackets,
+                                depth,
                                 in_string,
                                 escaped,
                             }
@@ -80,6 +256,7 @@ pub fn parse_json_from_stream(
                         JsonState::Active {
                             data,
                             num_brackets,
+                            depth,
                             in_string: false,
                             escaped,
                         }
@@ -89,21 +266,22 @@ pub fn parse_json_from_stream(
                         JsonState::Active {
                             data,
                             num_brackets,
+                            depth,
                             in_string: true,
                             escaped,
                         }
                     }
                     '\\' if !escaped => {
                         // If we encounter a backslash and the previous character wasn't a backslash
-                        // Set escaped flag
+                        // Set escaped flag so the next character can't close the string
+                        data.push(ch);
                         JsonState::Active {
                             data,
                             num_brackets,
+                            depth,
                             in_string,
                             escaped: true,
                         }
-                        // Do not push backslash character to data yet
-                        // It will be pushed in next iteration if necessary (when escaped character is not a quote)
                     }
                     _ => {
                         // Reset escaped flag (if it was set)
@@ -112,6 +290,7 @@ pub fn parse_json_from_stream(
                         JsonState::Active {
                             data,
                             num_brackets,
+                            depth,
                             in_string,
                             escaped: false,
                         }
@@ -158,7 +337,275 @@ pub fn parse_json_from_stream(
                 }
             }
         };
+        #[cfg(feature = "trace-parser")]
+        trace_transition(before, &json_state, ch, position.chars);
+        position.chars += 1;
+
+        if let JsonState::Active { data, depth, .. } = &json_state {
+            let kind = if *depth > limits.max_depth {
+                JsonLimitKind::Depth(limits.max_depth)
+            } else if data.len() > limits.max_bytes {
+                JsonLimitKind::Bytes(limits.max_bytes)
+            } else {
+                continue;
+            };
+            return Err(JsonLimitError {
+                kind,
+                prose: filtered_delta,
+            });
+        }
     }
 
-    (json_state, completed_json, filtered_delta)
+    Ok((json_state, completed_json, filtered_delta))
+}
+
+/// Returns the first JSON object embedded in `text`, found with the same rules
+/// as the streaming parser (so objects inside code spans are skipped).
+pub fn first_json_object(text: &str) -> Option<String> {
+    let (completed, _) = JsonStreamParser::default().feed(text).ok()?;
+    completed.into_iter().next().map(|c| c.json)
+}
+
+/// Returns the inside of a single code fence wrapping all of `text`, as models
+/// often send whole-message JSON, or `text` unchanged if it isn't wrapped in one.
+///
+/// ```
+/// use llm_plugin_utils::api::chat::strip_code_fence;
+///
+/// assert_eq!(strip_code_fence("```json\n{\"a\": 1}\n```"), "{\"a\": 1}");
+/// ```
+pub fn strip_code_fence(text: &str) -> &str {
+    code_fence(text).map_or(text, |(_, inner)| inner)
+}
+
+/// Like [`strip_code_fence`], but only strips a fence tagged with `language`
+/// (ignoring case), e.g. `"json"`.
+pub fn strip_code_fence_tagged<'a>(text: &'a str, language: &str) -> &'a str {
+    match code_fence(text) {
+        Some((tag, inner)) if tag.eq_ignore_ascii_case(language) => inner,
+        _ => text,
+    }
+}
+
+/// Splits a fence wrapping all of `text` into its language tag and contents.
+fn code_fence(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim();
+    let ticks = text.len() - text.trim_start_matches('`').len();
+    if ticks < 3 {
+        return None;
+    }
+
+    let fence = &text[..ticks];
+    let (tag, rest) = text[ticks..].split_once('\n')?;
+    let inner = rest.trim_end().strip_suffix(fence)?;
+    // A fence line inside means several blocks rather than one wrapping the text.
+    if inner
+        .lines()
+        .any(|line| line.trim_start().starts_with(fence))
+    {
+        return None;
+    }
+    Some((tag.trim(), inner.trim_end()))
+}
+
+/// Strips `//` and `/* */` comments and trailing commas from JSON-ish text, as
+/// models often emit, leaving string contents untouched.
+pub fn clean_lenient_json(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    while let Some(ch) = chars.next() {
+        if in_string {
+            output.push(ch);
+            match ch {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match ch {
+            '"' => {
+                in_string = true;
+                output.push(ch);
+            }
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|c| *c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = None;
+                for c in chars.by_ref() {
+                    if previous == Some('*') && c == '/' {
+                        break;
+                    }
+                    previous = Some(c);
+                }
+            }
+            '}' | ']' => {
+                // Drop a trailing comma, keeping any whitespace after it.
+                let trimmed = output.trim_end().len();
+                if output[..trimmed].ends_with(',') {
+                    output.remove(trimmed - 1);
+                }
+                output.push(ch);
+            }
+            _ => output.push(ch),
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_chars(parser: &mut JsonStreamParser, input: &str) -> (Vec<String>, String) {
+        let mut json = Vec::new();
+        let mut prose = String::new();
+        for ch in input.chars() {
+            let (completed, filtered) = parser.feed(&ch.to_string()).unwrap();
+            json.extend(completed.into_iter().map(|c| c.json));
+            prose.push_str(&filtered);
+        }
+        (json, prose)
+    }
+
+    #[test]
+    fn test_strip_code_fence() {
+        assert_eq!(
+            strip_code_fence("  ```json\n{\"a\": 1}\n```\n"),
+            "{\"a\": 1}"
+        );
+        assert_eq!(strip_code_fence("```\nplain\n```"), "plain");
+        assert_eq!(strip_code_fence_tagged("```JSON\n{}\n```", "json"), "{}");
+        assert_eq!(
+            strip_code_fence_tagged("```yaml\na: 1\n```", "json"),
+            "```yaml\na: 1\n```"
+        );
+
+        let two_blocks = "```json\n{}\n```\nthen\n```json\n[]\n```";
+        assert_eq!(strip_code_fence(two_blocks), two_blocks);
+        assert_eq!(strip_code_fence("Here: ```{}```"), "Here: ```{}```");
+    }
+
+    #[test]
+    fn test_fence_split_across_chunks() {
+        let mut parser = JsonStreamParser::default();
+
+        let mut prose = String::new();
+        for chunk in [
+            "Example:\n`",
+            "``json\n{\"a\": 1}",
+            "",
+            "  ",
+            "\n`",
+            "``\nDone",
+        ] {
+            let (json, filtered) = parser.feed(chunk).unwrap();
+            assert!(json.is_empty());
+            prose.push_str(&filtered);
+        }
+
+        assert_eq!(prose, "Example:\n```json\n{\"a\": 1}  \n```\nDone");
+    }
+
+    #[test]
+    fn test_fence_one_char_at_a_time() {
+        let mut parser = JsonStreamParser::default();
+
+        let input = "```json\n{\"ignored\": true}\n```\nResult: {\"a\": {\"b\": 2}}";
+        let (json, prose) = feed_chars(&mut parser, input);
+
+        assert_eq!(json, vec!["{\"a\": {\"b\": 2}}".to_string()]);
+        assert_eq!(prose, "```json\n{\"ignored\": true}\n```\nResult: ");
+    }
+
+    #[test]
+    fn test_inline_code_one_char_at_a_time() {
+        let mut parser = JsonStreamParser::default();
+
+        let (json, prose) = feed_chars(&mut parser, "Use `{}` like so: {\"x\": 0}");
+
+        assert_eq!(json, vec!["{\"x\": 0}".to_string()]);
+        assert_eq!(prose, "Use `{}` like so: ");
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = JsonLimits {
+            max_depth: 2,
+            max_bytes: 16,
+        };
+
+        let kind = |result: Result<_, JsonLimitError>| result.unwrap_err().kind;
+
+        let mut parser = JsonStreamParser::with_limits(limits);
+        assert_eq!(
+            kind(parser.feed("{\"a\": {\"b\": {")),
+            JsonLimitKind::Depth(2)
+        );
+        let (json, prose) = parser.feed("ok {\"a\": 1}").unwrap();
+        assert_eq!(json[0].json, "{\"a\": 1}");
+        assert_eq!(prose, "ok ");
+
+        let mut parser = JsonStreamParser::with_limits(limits);
+        assert_eq!(
+            kind(parser.feed("{\"a\": \"0123456789abcdef\"}")),
+            JsonLimitKind::Bytes(16)
+        );
+
+        let mut parser = JsonStreamParser::with_limits(limits);
+        assert_eq!(kind(parser.feed("{\"a\": [[")), JsonLimitKind::Depth(2));
+        let (json, _) = parser.feed("{\"a\": [\"[[\"]}").unwrap();
+        assert_eq!(json[0].json, "{\"a\": [\"[[\"]}");
+
+        // Prose ahead of the oversized object in the same chunk is kept.
+        let mut parser = JsonStreamParser::with_limits(limits);
+        let error = parser
+            .feed("Here you go: {\"a\": \"0123456789abcdef\"}")
+            .unwrap_err();
+        assert_eq!(error.kind, JsonLimitKind::Bytes(16));
+        assert_eq!(error.prose, "Here you go: ");
+    }
+
+    #[test]
+    fn test_escapes() {
+        let mut parser = JsonStreamParser::default();
+
+        let input = r#"{"a": "say \"hi\" {", "b": "\\"}"#;
+        let (json, _) = feed_chars(&mut parser, input);
+        assert_eq!(json, vec![input.to_string()]);
+    }
+
+    #[test]
+    fn test_clean_lenient_json() {
+        let input = "{\n  // the answer\n  \"a\": [1, 2,],\n  \"b\": \"// not /* a comment\", /* gone */\n}";
+        let value: serde_json::Value = serde_json::from_str(&clean_lenient_json(input)).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({ "a": [1, 2], "b": "// not /* a comment" })
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let mut parser = JsonStreamParser::default();
+
+        let input = "First {\"a\": 1}";
+        let (json, _) = parser.feed(input).unwrap();
+        assert_eq!(json[0].span, 6..14);
+        assert_eq!(&input[json[0].span.clone()], json[0].json);
+
+        let (json, _) = parser.feed(", then {\"b\": ").unwrap();
+        assert!(json.is_empty());
+        let (json, _) = parser.feed("2} and {}").unwrap();
+        assert_eq!(
+            json.iter().map(|c| c.span.clone()).collect::<Vec<_>>(),
+            vec![21..29, 34..36]
+        );
+    }
 }
@@ -0,0 +1,168 @@
+//! Compact embedding representations for large stores.
+//!
+//! Int8 quantization stores each dimension in one byte, a quarter of the size of
+//! `f32`. With OpenAI's unit-length embeddings the dot products it produces are
+//! typically within about 1% of the exact ones, which rarely changes the top few
+//! results but can reorder near-ties.
+//!
+//! Binary quantization keeps only the sign of each dimension, 1/32 of the size of
+//! `f32`, and compares vectors by Hamming distance. It's much coarser: use it to
+//! shortlist candidates and rerank those with the full embeddings.
+
+use super::embeddings::top_k;
+
+/// Quantizes `a` to `i8`, returning the values and the scale that maps them back,
+/// so that `a[i] ≈ values[i] as f32 * scale`.
+pub fn quantize_int8(a: &[f32]) -> (Vec<i8>, f32) {
+    let max = a.iter().fold(0f32, |max, x| max.max(x.abs()));
+    if max == 0. {
+        return (vec![0; a.len()], 0.);
+    }
+
+    let scale = max / i8::MAX as f32;
+    let values = a.iter().map(|x| (x / scale).round() as i8).collect();
+    (values, scale)
+}
+
+/// An embedding quantized with [`quantize_int8`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Int8Embedding {
+    pub values: Vec<i8>,
+    pub scale: f32,
+}
+
+impl Int8Embedding {
+    pub fn new(embedding: &[f32]) -> Self {
+        let (values, scale) = quantize_int8(embedding);
+        Self { values, scale }
+    }
+
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.values.iter().map(|v| *v as f32 * self.scale).collect()
+    }
+
+    /// Approximate dot product, summed in integer space and scaled once at the end.
+    pub fn dot(&self, other: &Int8Embedding) -> f32 {
+        let dot: i32 = self
+            .values
+            .iter()
+            .zip(&other.values)
+            .map(|(a, b)| *a as i32 * *b as i32)
+            .sum();
+        dot as f32 * self.scale * other.scale
+    }
+}
+
+impl AsRef<Int8Embedding> for Int8Embedding {
+    fn as_ref(&self) -> &Int8Embedding {
+        self
+    }
+}
+
+/// Like [`knn_search`](super::embeddings::knn_search), but over int8-quantized
+/// embeddings. The returned similarities are approximate.
+pub fn knn_search_int8<'a, U>(
+    query: &Int8Embedding,
+    content: impl Iterator<Item = &'a U>,
+    k: usize,
+) -> Vec<(&'a U, f32)>
+where
+    U: AsRef<Int8Embedding> + 'a,
+{
+    top_k(content.map(|item| (item, query.dot(item.as_ref()))), k)
+}
+
+/// An embedding reduced to one sign bit per dimension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryEmbedding {
+    pub bits: Vec<u64>,
+}
+
+impl BinaryEmbedding {
+    /// Sets a bit for every positive dimension of `embedding`.
+    pub fn new(embedding: &[f32]) -> Self {
+        let bits = embedding
+            .chunks(64)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, x)| **x > 0.)
+                    .fold(0u64, |bits, (i, _)| bits | 1 << i)
+            })
+            .collect();
+        Self { bits }
+    }
+
+    /// The number of dimensions whose signs differ.
+    pub fn hamming_distance(&self, other: &BinaryEmbedding) -> u32 {
+        self.bits
+            .iter()
+            .zip(&other.bits)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum()
+    }
+}
+
+impl AsRef<BinaryEmbedding> for BinaryEmbedding {
+    fn as_ref(&self) -> &BinaryEmbedding {
+        self
+    }
+}
+
+/// Returns the `k` items with the smallest Hamming distance to `query`, nearest
+/// first. Items at equal distance keep the order they had in `content`.
+pub fn knn_search_binary<'a, U>(
+    query: &BinaryEmbedding,
+    content: impl Iterator<Item = &'a U>,
+    k: usize,
+) -> Vec<(&'a U, u32)>
+where
+    U: AsRef<BinaryEmbedding> + 'a,
+{
+    let scored = content.map(|item| (item, -(query.hamming_distance(item.as_ref()) as f32)));
+    top_k(scored, k)
+        .into_iter()
+        .map(|(item, score)| (item, -score as u32))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::embeddings::knn_search;
+
+    #[test]
+    fn test_quantized_search() {
+        let (values, scale) = quantize_int8(&[0.5, -0.25, 0.]);
+        assert_eq!(values, vec![127, -64, 0]);
+        assert!((values[1] as f32 * scale + 0.25).abs() < 0.01);
+
+        let query = vec![0.6, 0.8, 0.];
+        let items = [
+            vec![0., 0.6, 0.8],
+            vec![0.8, 0.6, 0.],
+            vec![-0.6, -0.8, 0.],
+            vec![0.6, 0.8, 0.],
+        ];
+        let exact = knn_search(&query, items.iter(), 4);
+
+        let int8: Vec<_> = items.iter().map(|i| Int8Embedding::new(i)).collect();
+        let approximate = knn_search_int8(&Int8Embedding::new(&query), int8.iter(), 4);
+        for ((item, similarity), (expected, expected_similarity)) in
+            approximate.into_iter().zip(exact)
+        {
+            assert!((similarity - expected_similarity).abs() < 0.01);
+            assert!(item
+                .dequantize()
+                .iter()
+                .zip(expected)
+                .all(|(a, b)| (a - b).abs() < 0.01));
+        }
+
+        let binary: Vec<_> = items.iter().map(|i| BinaryEmbedding::new(i)).collect();
+        let results = knn_search_binary(&BinaryEmbedding::new(&query), binary.iter(), 2);
+        assert_eq!(results, vec![(&binary[1], 0), (&binary[3], 0)]);
+        assert_eq!(binary[0].hamming_distance(&binary[2]), 2);
+    }
+}
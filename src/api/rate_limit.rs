@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::chat::ChatUsage;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Sliding one-minute window over recent requests and their token usage.
+///
+/// Record each response's [`ChatUsage`] and call [`TokenRateLimiter::wait_time`]
+/// before the next request to stay under the configured tokens-per-minute and
+/// requests-per-minute limits. The limiter is `Send + Sync`, so it can be shared
+/// across tasks behind an `Arc`.
+#[derive(Debug)]
+pub struct TokenRateLimiter {
+    tokens_per_minute: u32,
+    requests_per_minute: u32,
+    window: Mutex<VecDeque<(Instant, u32)>>,
+}
+
+impl TokenRateLimiter {
+    pub fn new(tokens_per_minute: u32, requests_per_minute: u32) -> Self {
+        Self {
+            tokens_per_minute,
+            requests_per_minute,
+            window: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, usage: ChatUsage) {
+        self.record_at(usage, Instant::now());
+    }
+
+    /// How long to wait before the next request can be sent without exceeding either limit.
+    pub fn wait_time(&self) -> Duration {
+        self.wait_time_at(Instant::now())
+    }
+
+    /// Tokens recorded within the last minute.
+    pub fn tokens_in_window(&self) -> u32 {
+        let mut window = self.window.lock().unwrap();
+        prune(&mut window, Instant::now());
+        window.iter().map(|(_, tokens)| tokens).sum()
+    }
+
+    fn record_at(&self, usage: ChatUsage, now: Instant) {
+        let mut window = self.window.lock().unwrap();
+        prune(&mut window, now);
+        window.push_back((now, usage.total_tokens));
+    }
+
+    fn wait_time_at(&self, now: Instant) -> Duration {
+        let mut window = self.window.lock().unwrap();
+        prune(&mut window, now);
+
+        let until_expired = |at: Instant| (at + WINDOW).saturating_duration_since(now);
+
+        let mut wait = Duration::ZERO;
+
+        let requests = window.len() as u32;
+        if requests >= self.requests_per_minute {
+            let excess = (requests - self.requests_per_minute) as usize;
+            if let Some((at, _)) = window.get(excess) {
+                wait = wait.max(until_expired(*at));
+            }
+        }
+
+        let mut tokens: u32 = window.iter().map(|(_, tokens)| tokens).sum();
+        if tokens >= self.tokens_per_minute {
+            for (at, used) in window.iter() {
+                tokens -= used;
+                if tokens < self.tokens_per_minute {
+                    wait = wait.max(until_expired(*at));
+                    break;
+                }
+            }
+        }
+
+        wait
+    }
+}
+
+fn prune(window: &mut VecDeque<(Instant, u32)>, now: Instant) {
+    while let Some((at, _)) = window.front() {
+        if now.saturating_duration_since(*at) >= WINDOW {
+            window.pop_front();
+        } else {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(total_tokens: u32) -> ChatUsage {
+        ChatUsage {
+            total_tokens,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_wait_time() {
+        let limiter = TokenRateLimiter::new(1000, 3);
+        let start = Instant::now();
+
+        limiter.record_at(usage(600), start);
+        assert_eq!(limiter.wait_time_at(start), Duration::ZERO);
+
+        limiter.record_at(usage(500), start + Duration::from_secs(10));
+        assert_eq!(
+            limiter.wait_time_at(start + Duration::from_secs(20)),
+            Duration::from_secs(40)
+        );
+
+        limiter.record_at(usage(10), start + Duration::from_secs(61));
+        limiter.record_at(usage(10), start + Duration::from_secs(62));
+        assert_eq!(
+            limiter.wait_time_at(start + Duration::from_secs(62)),
+            Duration::from_secs(8)
+        );
+    }
+}
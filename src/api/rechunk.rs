@@ -0,0 +1,98 @@
+use futures::stream::{Stream, StreamExt};
+
+use super::chat::{ChatDelta, ChatStream};
+
+/// Where [`rechunk`] may split the streamed content.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkBoundary {
+    /// After whitespace, so words are never split.
+    #[default]
+    Word,
+    /// After whitespace that follows `.`, `!` or `?`, and after line breaks.
+    Sentence,
+}
+
+impl ChunkBoundary {
+    /// The byte offset just past the last boundary in `text`, if there is one.
+    fn split_point(&self, text: &str) -> Option<usize> {
+        let mut point = None;
+        let mut previous = None;
+        for (i, c) in text.char_indices() {
+            let boundary = match self {
+                Self::Word => c.is_whitespace(),
+                Self::Sentence => {
+                    c == '\n' || c.is_whitespace() && matches!(previous, Some('.' | '!' | '?'))
+                }
+            };
+            if boundary {
+                point = Some(i + c.len_utf8());
+            }
+            previous = Some(c);
+        }
+        point
+    }
+}
+
+/// Re-segments the content of a chat stream into whole words or sentences, e.g.
+/// for a smoother typing effect than the model's token-sized deltas.
+///
+/// Content is buffered until it reaches a boundary, and whatever is left is
+/// yielded when the stream ends. An error is passed on and ends the stream.
+pub fn rechunk<E>(
+    chunks: impl Stream<Item = Result<ChatStream, E>> + Unpin,
+    boundary: ChunkBoundary,
+) -> impl Stream<Item = Result<String, E>> {
+    futures::stream::unfold(Some((chunks, String::new())), move |state| async move {
+        let (mut chunks, mut buffer) = state?;
+        loop {
+            match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    let Some(ChatDelta::Content(content)) = chunk.delta() else {
+                        continue;
+                    };
+                    buffer.push_str(&content);
+                    if let Some(point) = boundary.split_point(&buffer) {
+                        let rest = buffer.split_off(point);
+                        return Some((Ok(buffer), Some((chunks, rest))));
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => return (!buffer.is_empty()).then_some((Ok(buffer), None)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn test_rechunk() {
+        let deltas = ["It's transp", "arent. Re", "ally!", " Next\nline", " end"];
+        let chunks = || {
+            futures::stream::iter(
+                deltas.map(|d| Ok::<_, std::convert::Infallible>(ChatStream::from_content(d))),
+            )
+        };
+
+        let words: Vec<_> = rechunk(chunks(), ChunkBoundary::Word)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            words,
+            vec!["It's ", "transparent. ", "Really! Next\n", "line ", "end"]
+        );
+
+        let sentences: Vec<_> = rechunk(chunks(), ChunkBoundary::Sentence)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            sentences,
+            vec!["It's transparent. ", "Really! Next\n", "line end"]
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Recording chat sessions and replaying them offline.
+//!
+//! [`SessionRecorder`] wraps a [`ChatBackend`] and writes every successful request
+//! and its response (or streamed chunks) to a JSONL transcript. [`SessionPlayer`]
+//! reads a transcript back and answers the same requests, in the same order,
+//! without touching the network, e.g. for deterministic examples and CI tests.
+
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::backend::{ChatBackend, ChunkStream};
+use super::chat::{ChatRequest, ChatResponse, ChatStream};
+
+/// One line of a session transcript.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SessionEntry {
+    Complete {
+        request: ChatRequest,
+        response: ChatResponse,
+    },
+    Stream {
+        request: ChatRequest,
+        chunks: Vec<ChatStream>,
+    },
+}
+
+impl SessionEntry {
+    fn request(&self) -> &ChatRequest {
+        match self {
+            Self::Complete { request, .. } | Self::Stream { request, .. } => request,
+        }
+    }
+}
+
+type SharedWriter = Arc<Mutex<Box<dyn Write + Send>>>;
+
+fn write_entry(
+    writer: &SharedWriter,
+    entry: &SessionEntry,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut writer = writer.lock().unwrap();
+    serde_json::to_writer(&mut *writer, entry)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// A [`ChatBackend`] that forwards to `inner` and records each exchange.
+///
+/// Failed requests aren't recorded. A stream is recorded when it ends or is
+/// dropped, with the chunks read so far (e.g. up to the JSON object
+/// [`ChatRequest::stream_json_with`] was waiting for), unless it failed.
+pub struct SessionRecorder<B> {
+    inner: B,
+    writer: SharedWriter,
+}
+
+impl<B: ChatBackend> SessionRecorder<B> {
+    pub fn new(inner: B, writer: impl Write + Send + 'static) -> Self {
+        Self {
+            inner,
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+        }
+    }
+
+    /// Records to a new file at `path`, replacing any existing one.
+    pub fn create(inner: B, path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = std::fs::File::create(path)?;
+        Ok(Self::new(inner, std::io::BufWriter::new(file)))
+    }
+}
+
+impl<B: ChatBackend> ChatBackend for SessionRecorder<B> {
+    fn complete(
+        &self,
+        request: ChatRequest,
+    ) -> BoxFuture<'_, Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>>> {
+        async move {
+            let response = self.inner.complete(request.clone()).await?;
+            let entry = SessionEntry::Complete {
+                request,
+                response: response.clone(),
+            };
+            write_entry(&self.writer, &entry)?;
+            Ok(response)
+        }
+        .boxed()
+    }
+
+    fn stream(
+        &self,
+        request: ChatRequest,
+    ) -> Result<ChunkStream, Box<dyn std::error::Error + Send + Sync>> {
+        let chunks = self.inner.stream(request.clone())?;
+        let recording = StreamRecording {
+            writer: self.writer.clone(),
+            request: Some(request),
+            chunks: Vec::new(),
+        };
+
+        let recorded = futures::stream::unfold(Some((chunks, recording)), |state| async move {
+            let (mut chunks, mut recording) = state?;
+            match chunks.next().await? {
+                Ok(chunk) => {
+                    recording.chunks.push(chunk.clone());
+                    Some((Ok(chunk), Some((chunks, recording))))
+                }
+                Err(e) => {
+                    recording.request = None;
+                    Some((Err(e), None))
+                }
+            }
+        });
+        Ok(recorded.boxed())
+    }
+}
+
+/// The chunks of a stream read so far, written out when the stream is dropped
+/// unless it failed (in which case `request` is cleared).
+struct StreamRecording {
+    writer: SharedWriter,
+    request: Option<ChatRequest>,
+    chunks: Vec<ChatStream>,
+}
+
+impl Drop for StreamRecording {
+    fn drop(&mut self) {
+        let Some(request) = self.request.take() else {
+            return;
+        };
+        let entry = SessionEntry::Stream {
+            request,
+            chunks: std::mem::take(&mut self.chunks),
+        };
+        if let Err(e) = write_entry(&self.writer, &entry) {
+            log::warn!("error recording chat stream: {e}");
+        }
+    }
+}
+
+/// A [`ChatBackend`] that replays a transcript written by [`SessionRecorder`].
+///
+/// Entries are served in the order they were recorded, whatever the request. A
+/// request that differs from the recorded one is logged as a warning, since the
+/// replay has likely drifted from the session.
+#[derive(Debug)]
+pub struct SessionPlayer {
+    entries: Mutex<VecDeque<SessionEntry>>,
+}
+
+impl SessionPlayer {
+    pub fn from_reader(
+        reader: impl BufRead,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut entries = VecDeque::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push_back(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(Self {
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let file = std::fs::File::open(path)?;
+        Self::from_reader(std::io::BufReader::new(file))
+    }
+
+    /// How many recorded entries haven't been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    fn next_entry(
+        &self,
+        request: &ChatRequest,
+    ) -> Result<SessionEntry, Box<dyn std::error::Error + Send + Sync>> {
+        let entry = self
+            .entries
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or("no recorded entries left")?;
+        if entry.request().cache_key() != request.cache_key() {
+            log::warn!("replayed request differs from the recorded one");
+        }
+        Ok(entry)
+    }
+}
+
+impl ChatBackend for SessionPlayer {
+    fn complete(
+        &self,
+        request: ChatRequest,
+    ) -> BoxFuture<'_, Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>>> {
+        let response = match self.next_entry(&request) {
+            Ok(SessionEntry::Complete { response, .. }) => Ok(response),
+            Ok(SessionEntry::Stream { .. }) => {
+                Err("expected a recorded completion, found a stream".into())
+            }
+            Err(e) => Err(e),
+        };
+        async move { response }.boxed()
+    }
+
+    fn stream(
+        &self,
+        request: ChatRequest,
+    ) -> Result<ChunkStream, Box<dyn std::error::Error + Send + Sync>> {
+        match self.next_entry(&request)? {
+            SessionEntry::Stream { chunks, .. } => {
+                Ok(futures::stream::iter(chunks.into_iter().map(Ok)).boxed())
+            }
+            SessionEntry::Complete { .. } => {
+                Err("expected a recorded stream, found a completion".into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::backend::MockBackend;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join("llm-plugin-utils-test-session.jsonl");
+
+        let backend = MockBackend::new();
+        backend.push_content_stream(["Here: {\"a\"", ": 1}"]);
+        let response: ChatResponse = serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "object": "chat.completion",
+            "created": 0,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hi!" },
+                "finish_reason": "stop"
+            }]
+        }))
+        .unwrap();
+        backend.push_response(response);
+
+        let request = || ChatRequest::builder().user("Hi").stream(true).build();
+        {
+            let recorder = SessionRecorder::create(backend, &path).unwrap();
+            let streamed = request().stream_json_with(&recorder).await.unwrap();
+            assert_eq!(streamed.json.as_deref(), Some("{\"a\": 1}"));
+            let completed = request().request_with(&recorder).await.unwrap();
+            assert_eq!(completed.assistant_text().as_deref(), Some("Hi!"));
+        }
+
+        let player = SessionPlayer::open(&path).unwrap();
+        assert_eq!(player.remaining(), 2);
+        let streamed = request().stream_json_with(&player).await.unwrap();
+        assert_eq!(streamed.antecedent, "Here: ");
+        assert_eq!(streamed.json.as_deref(), Some("{\"a\": 1}"));
+        let completed = request().request_with(&player).await.unwrap();
+        assert_eq!(completed.assistant_text().as_deref(), Some("Hi!"));
+        assert!(request().request_with(&player).await.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+use futures::stream::{Chunks, Stream, StreamExt};
+use reqwest::Client;
+
+use super::embeddings::{knn_search, string_embeddings, Embedding};
+
+#[derive(Debug, Clone)]
+pub struct StoredEmbedding<K> {
+    pub id: K,
+    pub embedding: Vec<f32>,
+}
+
+impl<K> Embedding for StoredEmbedding<K> {
+    fn embedding(&self) -> &[f32] {
+        &self.embedding
+    }
+}
+
+/// A small in-memory vector store searched exhaustively with [`knn_search`].
+#[derive(Debug, Clone)]
+pub struct EmbeddingStore<K> {
+    entries: Vec<StoredEmbedding<K>>,
+}
+
+impl<K> Default for EmbeddingStore<K> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<K> EmbeddingStore<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, id: K, embedding: Vec<f32>) {
+        self.entries.push(StoredEmbedding { id, embedding });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &StoredEmbedding<K>> {
+        self.entries.iter()
+    }
+
+    /// Returns the ids of the `k` entries most similar to `query`, most similar first.
+    pub fn search<T: Embedding>(&self, query: &T, k: usize) -> Vec<(&K, f32)> {
+        knn_search(query, self.entries.iter(), k)
+            .into_iter()
+            .map(|(entry, similarity)| (&entry.id, similarity))
+            .collect()
+    }
+}
+
+impl<K: PartialEq> EmbeddingStore<K> {
+    /// Removes every entry for `id`, returning whether there was one.
+    pub fn remove(&mut self, id: &K) -> bool {
+        let len = self.entries.len();
+        // `retain` preserves insertion order, which search uses to break ties.
+        self.entries.retain(|entry| entry.id != *id);
+        self.entries.len() < len
+    }
+
+    /// Replaces the embedding for `id`, inserting it if it's new. Returns whether
+    /// `id` already existed.
+    pub fn update(&mut self, id: K, embedding: Vec<f32>) -> bool {
+        match self.entries.iter_mut().find(|entry| entry.id == id) {
+            Some(entry) => {
+                entry.embedding = embedding;
+                true
+            }
+            None => {
+                self.insert(id, embedding);
+                false
+            }
+        }
+    }
+}
+
+/// Builds an [`EmbeddingStore`] from a stream of `(id, text)` documents, embedding
+/// them in batches as they arrive.
+///
+/// Call [`StreamingIndex::next_batch`] to embed the next batch; the index can be
+/// searched at any point with what has been embedded so far.
+pub struct StreamingIndex<'a, S: Stream, K> {
+    documents: Chunks<S>,
+    client: &'a Client,
+    api_key: &'a str,
+    store: EmbeddingStore<K>,
+}
+
+impl<'a, S, K> StreamingIndex<'a, S, K>
+where
+    S: Stream<Item = (K, String)> + Unpin,
+{
+    /// A `batch_size` of zero is treated as one.
+    pub fn new(documents: S, batch_size: usize, client: &'a Client, api_key: &'a str) -> Self {
+        Self {
+            documents: documents.chunks(batch_size.max(1)),
+            client,
+            api_key,
+            store: EmbeddingStore::new(),
+        }
+    }
+
+    /// Embeds the next batch of documents, returning how many were added, or
+    /// `None` once the document stream is exhausted.
+    pub async fn next_batch(
+        &mut self,
+    ) -> Option<Result<usize, Box<dyn std::error::Error + Send + Sync>>> {
+        let batch = self.documents.next().await?;
+        let (ids, texts): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+
+        let embeddings = match string_embeddings(texts.into_iter(), self.client, self.api_key).await
+        {
+            Ok(embeddings) => embeddings,
+            Err(e) => return Some(Err(e)),
+        };
+
+        Some(insert_batch(&mut self.store, ids, embeddings))
+    }
+
+    /// Embeds every remaining document.
+    pub async fn finish(
+        mut self,
+    ) -> Result<EmbeddingStore<K>, Box<dyn std::error::Error + Send + Sync>> {
+        while let Some(added) = self.next_batch().await {
+            added?;
+        }
+        Ok(self.store)
+    }
+
+    pub fn search<T: Embedding>(&self, query: &T, k: usize) -> Vec<(&K, f32)> {
+        self.store.search(query, k)
+    }
+
+    pub fn store(&self) -> &EmbeddingStore<K> {
+        &self.store
+    }
+
+    pub fn into_store(self) -> EmbeddingStore<K> {
+        self.store
+    }
+}
+
+/// Adds one embedding per id, failing without adding any if the counts differ.
+fn insert_batch<K>(
+    store: &mut EmbeddingStore<K>,
+    ids: Vec<K>,
+    embeddings: Vec<Vec<f32>>,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    if ids.len() != embeddings.len() {
+        return Err(format!(
+            "expected {} embeddings, got {}",
+            ids.len(),
+            embeddings.len()
+        )
+        .into());
+    }
+
+    let added = embeddings.len();
+    for (id, embedding) in ids.into_iter().zip(embeddings) {
+        store.insert(id, embedding);
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_search() {
+        let mut store = EmbeddingStore::new();
+        store.insert("x", vec![1.0, 0.0]);
+        store.insert("y", vec![0.0, 1.0]);
+        store.insert("xy", vec![0.7, 0.7]);
+
+        let ids: Vec<_> = store
+            .search(&vec![1.0, 0.1], 2)
+            .into_iter()
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(ids, vec!["x", "xy"]);
+
+        assert!(store.remove(&"x"));
+        assert!(!store.remove(&"x"));
+        assert!(store.update("y", vec![1.0, 0.0]));
+        assert!(!store.update("z", vec![-1.0, 0.0]));
+
+        let ids: Vec<_> = store
+            .search(&vec![1.0, 0.1], 3)
+            .into_iter()
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(ids, vec!["y", "xy", "z"]);
+    }
+
+    #[test]
+    fn test_store_remove_duplicates() {
+        let mut store = EmbeddingStore::new();
+        store.insert("x", vec![1.0, 0.0]);
+        store.insert("y", vec![0.0, 1.0]);
+        store.insert("x", vec![0.9, 0.1]);
+
+        assert!(store.remove(&"x"));
+        assert_eq!(store.len(), 1);
+        let ids: Vec<_> = store
+            .search(&vec![1.0, 0.0], 3)
+            .into_iter()
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(ids, vec!["y"]);
+        assert!(!store.remove(&"x"));
+    }
+
+    #[tokio::test]
+    async fn test_streaming_index_batches() {
+        let client = Client::new();
+        let mut index = StreamingIndex::new(
+            futures::stream::iter(Vec::<(u32, String)>::new()),
+            0,
+            &client,
+            "key",
+        );
+        assert!(index.next_batch().await.is_none());
+
+        let mut store = EmbeddingStore::new();
+        let error = insert_batch(&mut store, vec!["a", "b"], vec![vec![1.0]]).unwrap_err();
+        assert_eq!(error.to_string(), "expected 2 embeddings, got 1");
+        assert!(store.is_empty());
+        assert_eq!(
+            insert_batch(&mut store, vec!["a"], vec![vec![1.0]]).unwrap(),
+            1
+        );
+    }
+}
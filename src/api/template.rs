@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use super::chat::ChatMessage;
+
+/// A prompt with `{{name}}` placeholders, filled in by [`PromptTemplate::render`].
+///
+/// Whitespace inside the braces is ignored, and `\{{` renders a literal `{{`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptTemplate {
+    template: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    /// A placeholder had no matching variable.
+    MissingVariable(String),
+    /// A `{{` at this byte offset was never closed.
+    Unclosed(usize),
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingVariable(name) => write!(f, "no value for template variable {name:?}"),
+            Self::Unclosed(offset) => write!(f, "unclosed placeholder at byte {offset}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl PromptTemplate {
+    pub fn new(template: impl Into<String>) -> Self {
+        Self {
+            template: template.into(),
+        }
+    }
+
+    pub fn render(&self, vars: &HashMap<&str, String>) -> Result<String, TemplateError> {
+        let mut output = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+
+        while let Some(start) = rest.find("{{") {
+            if let Some(literal) = rest[..start].strip_suffix('\\') {
+                output.push_str(literal);
+                output.push_str("{{");
+                rest = &rest[start + 2..];
+                continue;
+            }
+
+            output.push_str(&rest[..start]);
+            let offset = self.template.len() - rest.len() + start;
+            let placeholder = &rest[start + 2..];
+            let end = placeholder
+                .find("}}")
+                .ok_or(TemplateError::Unclosed(offset))?;
+
+            let name = placeholder[..end].trim();
+            let value = vars
+                .get(name)
+                .ok_or_else(|| TemplateError::MissingVariable(name.to_string()))?;
+            output.push_str(value);
+            rest = &placeholder[end + 2..];
+        }
+
+        output.push_str(rest);
+        Ok(output)
+    }
+
+    pub fn system(&self, vars: &HashMap<&str, String>) -> Result<ChatMessage, TemplateError> {
+        Ok(ChatMessage::new_system(self.render(vars)?))
+    }
+
+    pub fn user(&self, vars: &HashMap<&str, String>) -> Result<ChatMessage, TemplateError> {
+        Ok(ChatMessage::new_user(self.render(vars)?, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let vars = HashMap::from([("name", "Ada".to_string()), ("task", "math".to_string())]);
+
+        let template = PromptTemplate::new("Hi {{name}}, let's do {{ task }}. \\{{name}} stays.");
+        assert_eq!(
+            template.render(&vars).unwrap(),
+            "Hi Ada, let's do math. {{name}} stays."
+        );
+
+        assert_eq!(
+            PromptTemplate::new("{{missing}}").render(&vars),
+            Err(TemplateError::MissingVariable("missing".into()))
+        );
+        assert_eq!(
+            PromptTemplate::new("ok {{name").render(&vars),
+            Err(TemplateError::Unclosed(3))
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton, CoreBPE};
+
+use super::chat::ChatModel;
+
+/// Tokens the chat format adds around every message.
+pub(crate) const TOKENS_PER_MESSAGE: usize = 3;
+/// Extra tokens charged when a message carries a `name`.
+pub(crate) const TOKENS_PER_NAME: usize = 1;
+
+impl ChatModel {
+    fn with_tokenizer<R>(&self, f: impl FnOnce(&CoreBPE) -> R) -> R {
+        let bpe = if self.is_reasoning() {
+            o200k_base_singleton()
+        } else {
+            cl100k_base_singleton()
+        };
+        let bpe = bpe.lock();
+        f(&bpe)
+    }
+}
+
+/// Counts the tokens in `text` using the model's tokenizer.
+pub fn count_tokens(text: &str, model: ChatModel) -> usize {
+    model.with_tokenizer(|bpe| bpe.encode_with_special_tokens(text).len())
+}
+
+/// Counts the tokens in `text` as OpenAI's embedding models see them.
+pub fn count_embedding_tokens(text: &str) -> usize {
+    // Both ada-002 and the text-embedding-3 models use cl100k_base.
+    let bpe = cl100k_base_singleton();
+    let bpe = bpe.lock();
+    bpe.encode_with_special_tokens(text).len()
+}
+
+/// Splits `text` into chunks of at most `max_tokens` tokens, each starting with
+/// the last `overlap` tokens of the previous one, e.g. for map-reduce
+/// summarization of an input that doesn't fit in the context window.
+///
+/// Within the last half of each chunk's window, a split after a paragraph break
+/// is preferred over one after a sentence, and either over a split mid-sentence.
+///
+/// # Panics
+///
+/// Panics if `overlap` isn't smaller than `max_tokens`.
+pub fn split_by_tokens(
+    text: &str,
+    model: ChatModel,
+    max_tokens: usize,
+    overlap: usize,
+) -> Vec<String> {
+    assert!(
+        overlap < max_tokens,
+        "overlap ({overlap}) must be smaller than max_tokens ({max_tokens})"
+    );
+
+    // Byte offset of each token's start, plus the end of the text. A token can
+    // end partway through a character, in which case the offset stays at the
+    // character's start until the tokens completing it have been decoded.
+    let offsets: Vec<usize> = model.with_tokenizer(|bpe| {
+        let mut pending = Vec::new();
+        std::iter::once(0)
+            .chain(
+                bpe.encode_with_special_tokens(text)
+                    .into_iter()
+                    .scan(0, |offset, token| {
+                        pending.push(token);
+                        if let Ok(decoded) = bpe.decode(pending.clone()) {
+                            *offset += decoded.len();
+                            pending.clear();
+                        }
+                        Some(*offset)
+                    }),
+            )
+            .collect()
+    });
+    let offset = |token: usize| offsets[token];
+    let tokens = offsets.len() - 1;
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < tokens {
+        let window_end = (start + max_tokens).min(tokens);
+        let end = if window_end == tokens {
+            tokens
+        } else {
+            // `max_by_key` returns the last of equally ranked ends, so this
+            // takes the latest split of the best kind.
+            (start + max_tokens / 2 + 1..=window_end)
+                .max_by_key(|end| boundary_rank(text, offset(*end)))
+                .unwrap_or(window_end)
+        };
+
+        let chunk = &text[offset(start)..offset(end)];
+        if !chunk.is_empty() {
+            chunks.push(chunk.to_string());
+        }
+        if end == tokens {
+            break;
+        }
+        start = end.saturating_sub(overlap).max(start + 1);
+    }
+    chunks
+}
+
+/// How good a place `offset` is to end a chunk: 2 after a paragraph, 1 after a
+/// sentence, 0 otherwise.
+fn boundary_rank(text: &str, offset: usize) -> u8 {
+    let (before, after) = text.split_at(offset);
+    if before.trim_end_matches([' ', '\t']).ends_with("\n\n") {
+        2
+    } else if before.trim_end().ends_with(['.', '!', '?']) && after.starts_with(char::is_whitespace)
+    {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_by_tokens() {
+        let model = ChatModel::GPT4;
+        let text = "The first paragraph has two sentences. This is the second one.\n\n\
+                    The next paragraph is a little longer than that and keeps going for a while. \
+                    It ends here.";
+
+        let chunks = split_by_tokens(text, model, 20, 0);
+        assert_eq!(chunks.concat(), text);
+        assert!(chunks.iter().all(|c| count_tokens(c, model) <= 20));
+        assert_eq!(
+            chunks[0],
+            "The first paragraph has two sentences. This is the second one.\n\n"
+        );
+        assert!(chunks[1].ends_with('.'));
+
+        let overlapping = split_by_tokens(text, model, 20, 4);
+        for pair in overlapping.windows(2) {
+            assert!((1..pair[1].len()).any(|k| pair[0].ends_with(&pair[1][..k])));
+        }
+
+        // Without any boundaries, chunks fill their whole window.
+        let text = "word ".repeat(100);
+        let text = text.trim_end();
+        let chunks = split_by_tokens(text, model, 20, 0);
+        assert_eq!(chunks.concat(), text);
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|c| count_tokens(c, model) == 20));
+
+        // Tokens that split a character still yield valid chunks.
+        let text = "日本語のテキスト".repeat(10);
+        let chunks = split_by_tokens(&text, model, 7, 0);
+        assert_eq!(chunks.concat(), text);
+
+        assert_eq!(split_by_tokens("Short.", model, 20, 4), vec!["Short."]);
+        assert!(split_by_tokens("", model, 20, 4).is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+use super::chat::{AssistantContent, ChatMessage};
+
+/// How [`render_transcript`] lays out each message.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptFormat {
+    /// `<|im_start|>role name=...` blocks, for backends that take a single
+    /// ChatML prompt.
+    ChatML,
+    /// A bold role heading per message, separated by blank lines.
+    Markdown,
+    /// `Role: content`, one message per line.
+    #[default]
+    Plain,
+}
+
+/// Renders `messages` as one readable string, e.g. for logging or for backends
+/// that take a single prompt.
+///
+/// Names (including function names and tool call ids) are shown next to the
+/// role, and function and tool calls are rendered as `name(arguments)`.
+pub fn render_transcript(messages: &[ChatMessage], format: TranscriptFormat) -> String {
+    let rendered = messages.iter().map(|message| {
+        let (role, name, content) = parts(message);
+        match format {
+            TranscriptFormat::ChatML => match name {
+                Some(name) => format!("<|im_start|>{role} name={name}\n{content}<|im_end|>"),
+                None => format!("<|im_start|>{role}\n{content}<|im_end|>"),
+            },
+            TranscriptFormat::Markdown => match name {
+                Some(name) => format!("**{} ({name}):**\n{content}", capitalize(role)),
+                None => format!("**{}:**\n{content}", capitalize(role)),
+            },
+            TranscriptFormat::Plain => match name {
+                Some(name) => format!("{} ({name}): {content}", capitalize(role)),
+                None => format!("{}: {content}", capitalize(role)),
+            },
+        }
+    });
+
+    let separator = match format {
+        TranscriptFormat::ChatML | TranscriptFormat::Plain => "\n",
+        TranscriptFormat::Markdown => "\n\n",
+    };
+    rendered.collect::<Vec<_>>().join(separator)
+}
+
+fn parts(message: &ChatMessage) -> (&'static str, Option<&str>, String) {
+    match message {
+        ChatMessage::User { content, name } => ("user", name.as_deref(), content.clone()),
+        ChatMessage::System { content } => ("system", None, content.clone()),
+        ChatMessage::Assistant { content, name } => {
+            let content = match content {
+                AssistantContent::FunctionCall { function_call } => {
+                    format!("{}({})", function_call.name(), function_call.arguments())
+                }
+                AssistantContent::Audio { audio } => audio.transcript.clone(),
+                AssistantContent::ToolCalls {
+                    content,
+                    tool_calls,
+                } => content
+                    .iter()
+                    .cloned()
+                    .chain(tool_calls.iter().map(|call| {
+                        let function = call.function();
+                        format!("{}({})", function.name(), function.arguments())
+                    }))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                content => content.text().unwrap_or_default(),
+            };
+            ("assistant", name.as_deref(), content)
+        }
+        ChatMessage::Function { content, name } => {
+            ("function", Some(name.as_str()), content.clone())
+        }
+        ChatMessage::Tool {
+            content,
+            tool_call_id,
+        } => ("tool", Some(tool_call_id.as_str()), content.clone()),
+    }
+}
+
+fn capitalize(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_transcript() {
+        let messages = [
+            ChatMessage::new_system("Be brief."),
+            ChatMessage::new_user("Weather?", Some("ada".into())),
+            ChatMessage::new_function("Sunny", "weather"),
+            ChatMessage::new_assistant("It's sunny."),
+        ];
+
+        assert_eq!(
+            render_transcript(&messages, TranscriptFormat::Plain),
+            "System: Be brief.\nUser (ada): Weather?\nFunction (weather): Sunny\nAssistant: It's sunny."
+        );
+        assert_eq!(
+            render_transcript(&messages[2..], TranscriptFormat::Markdown),
+            "**Function (weather):**\nSunny\n\n**Assistant:**\nIt's sunny."
+        );
+        assert_eq!(
+            render_transcript(&messages[..1], TranscriptFormat::ChatML),
+            "<|im_start|>system\nBe brief.<|im_end|>"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    Extension, Router,
+};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::{AuthorizationType, ManifestAuth};
+
+/// The bearer token a plugin expects on its API routes when using
+/// [`ManifestAuth::ServiceHttp`](crate::ManifestAuth::ServiceHttp).
+///
+/// Provide it to your routes as an extension:
+///
+/// ```ignore
+/// Router::new()
+///     .route("/todos", get(list_todos))
+///     .layer(Extension(PluginAuthToken::new(token)));
+/// ```
+#[derive(Clone)]
+pub struct PluginAuthToken(Arc<str>);
+
+impl std::fmt::Debug for PluginAuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PluginAuthToken(***)")
+    }
+}
+
+impl PluginAuthToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(Arc::from(token.into()))
+    }
+
+    pub fn verify(&self, candidate: &str) -> bool {
+        constant_time_eq(self.0.as_bytes(), candidate.as_bytes())
+    }
+}
+
+/// Extractor that succeeds only if the request carries `Authorization: Bearer <token>`
+/// matching the configured [`PluginAuthToken`].
+///
+/// Rejects with `401 Unauthorized` on a missing or mismatched token, and with
+/// `500 Internal Server Error` if no [`PluginAuthToken`] extension was configured.
+#[derive(Debug, Clone, Copy)]
+pub struct VerifiedPluginAuth;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for VerifiedPluginAuth
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .extensions
+            .get::<PluginAuthToken>()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let candidate = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        if token.verify(candidate.trim()) {
+            Ok(VerifiedPluginAuth)
+        } else {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// Service-level auth for a plugin: the verification token OpenAI issued for the
+/// manifest, and the bearer token OpenAI sends on every API request.
+///
+/// Building both the manifest's `auth` and the API's enforcement from one value
+/// keeps them from drifting apart.
+#[derive(Clone)]
+pub struct ServiceAuth {
+    pub verification_token: String,
+    pub access_token: PluginAuthToken,
+}
+
+impl std::fmt::Debug for ServiceAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServiceAuth")
+            .field("verification_token", &"***")
+            .field("access_token", &self.access_token)
+            .finish()
+    }
+}
+
+impl ServiceAuth {
+    pub fn new(verification_token: impl Into<String>, access_token: impl Into<String>) -> Self {
+        Self {
+            verification_token: verification_token.into(),
+            access_token: PluginAuthToken::new(access_token),
+        }
+    }
+
+    /// The manifest `auth` for this plugin.
+    pub fn manifest_auth(&self) -> ManifestAuth {
+        ManifestAuth::ServiceHttp {
+            authorization_type: AuthorizationType::Bearer,
+            verification_tokens: BTreeMap::from([(
+                "openai".to_string(),
+                self.verification_token.clone(),
+            )]),
+        }
+    }
+
+    /// Requires the access token on every route of `router`, rejecting other
+    /// requests with `401 Unauthorized`.
+    pub fn protect<S>(&self, router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        router
+            .route_layer(middleware::from_fn(require_plugin_auth))
+            .layer(Extension(self.access_token.clone()))
+    }
+}
+
+async fn require_plugin_auth(_: VerifiedPluginAuth, request: Request, next: Next) -> Response {
+    next.run(request).await
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    async fn extract(header: Option<&str>) -> Result<VerifiedPluginAuth, StatusCode> {
+        let mut request = Request::builder().extension(PluginAuthToken::new("secret"));
+        if let Some(header) = header {
+            request = request.header(AUTHORIZATION, header);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+        VerifiedPluginAuth::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn test_verified_plugin_auth() {
+        assert!(extract(Some("Bearer secret")).await.is_ok());
+        assert_eq!(
+            extract(Some("Bearer wrong")).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(extract(None).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_service_auth() {
+        use axum::{body::Body, routing::get};
+        use tower::ServiceExt;
+
+        let auth = ServiceAuth::new("verify-me", "secret");
+        assert_eq!(
+            serde_json::to_value(auth.manifest_auth()).unwrap(),
+            serde_json::json!({
+                "type": "service_http",
+                "authorization_type": "bearer",
+                "verification_tokens": { "openai": "verify-me" }
+            })
+        );
+
+        let router = auth.protect(Router::new().route("/todos", get(|| async { "[]" })));
+        let status = |header: Option<&'static str>| {
+            let mut request = Request::builder().uri("/todos");
+            if let Some(header) = header {
+                request = request.header(AUTHORIZATION, header);
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let router = router.clone();
+            async move { router.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(status(Some("Bearer secret")).await, StatusCode::OK);
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+
+        let debug = format!("{auth:?}");
+        assert!(!debug.contains("verify-me") && !debug.contains("secret"));
+    }
+}
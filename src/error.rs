@@ -0,0 +1,101 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+/// An error returned from a plugin's own API routes, serialized as
+/// `{ "error": { "message": ..., "code": ... } }` so the model sees a consistent shape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginError {
+    pub status: StatusCode,
+    pub message: String,
+    pub code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PluginErrorBody {
+    error: PluginErrorDetails,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PluginErrorDetails {
+    message: String,
+    code: Option<String>,
+}
+
+impl PluginError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            code: None,
+        }
+    }
+
+    pub fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message).with_code("bad_request")
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, message).with_code("unauthorized")
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message).with_code("not_found")
+    }
+
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::TOO_MANY_REQUESTS, message).with_code("rate_limited")
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message).with_code("internal_error")
+    }
+}
+
+impl std::fmt::Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.status)
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+impl IntoResponse for PluginError {
+    fn into_response(self) -> Response {
+        let body = PluginErrorBody {
+            error: PluginErrorDetails {
+                message: self.message,
+                code: self.code,
+            },
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_plugin_error_response() {
+        let response = PluginError::not_found("no such todo").into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": { "message": "no such todo", "code": "not_found" } })
+        );
+    }
+}
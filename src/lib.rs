@@ -1,32 +1,79 @@
 use axum::{
-    body::Full,
-    body::HttpBody,
-    extract::State,
+    body::Body,
+    extract::{MatchedPath, State},
     http::StatusCode,
     response::{IntoResponse, Response},
     routing::get,
-    Json, Router,
+    Router,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use typed_builder::TypedBuilder;
 use url::Url;
-use utoipa::openapi::OpenApi;
+use utoipa::openapi::{OpenApi, Server};
 
 pub mod api;
+pub mod auth;
+pub mod error;
+pub mod sse;
 
-pub use api::chat::{ChatMessage, ChatRequest};
-pub use api::embeddings::{knn_search, string_embeddings, EmbeddingRequest};
+#[cfg(feature = "ndarray")]
+pub use api::array::{array_to_embeddings, embeddings_to_array};
+pub use api::azure::AzureConfig;
+#[cfg(feature = "mock")]
+pub use api::backend::MockBackend;
+pub use api::backend::{ChatBackend, OpenAiBackend};
+pub use api::chat::{race_requests, strip_code_fence, ChatMessage, ChatRequest};
+pub use api::client::{
+    default_client, default_client_with_timeout, validate_api_key, ApiConfig, KeyError,
+};
+pub use api::context::{format_context, ContextFormat};
+pub use api::embeddings::{
+    centroids, classify, dot_product_at_least, embed_with_models, knn_search, knn_search_by,
+    knn_search_owned, knn_search_weighted, multi_query_search, pairwise_similarity,
+    string_embeddings, string_embeddings_opts, string_embeddings_with_progress, threshold_search,
+    EmbeddingBatchOptions, EmbeddingRequest, Reduction, SimilarityMetric,
+};
+pub use api::error::ApiError;
+pub use api::hooks::{Hooks, RequestMeta, ResponseMeta};
+pub use api::models::{list_models, ModelInfo};
+pub use api::moderation::{ModerationRequest, ModerationResponse};
+pub use api::quantize::{
+    knn_search_binary, knn_search_int8, quantize_int8, BinaryEmbedding, Int8Embedding,
+};
+pub use api::rate_limit::TokenRateLimiter;
+pub use api::rechunk::{rechunk, ChunkBoundary};
+pub use api::session::{SessionPlayer, SessionRecorder};
+pub use api::store::{EmbeddingStore, StreamingIndex};
+pub use api::template::{PromptTemplate, TemplateError};
+pub use api::transcript::{render_transcript, TranscriptFormat};
+pub use auth::{PluginAuthToken, ServiceAuth, VerifiedPluginAuth};
+pub use error::PluginError;
+pub use sse::{chat_sse_response, chat_sse_response_with};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum ManifestAuth {
     None,
     UserHttp,
-    ServiceHttp,
+    /// OpenAI authenticates to the API with a service-level token, and proves the
+    /// plugin is yours with the `verification_tokens` it issued, keyed by service
+    /// (e.g. `"openai"`). See [`ServiceAuth`] to serve both from one source.
+    ServiceHttp {
+        authorization_type: AuthorizationType,
+        verification_tokens: BTreeMap<String, String>,
+    },
     Oauth,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthorizationType {
+    Bearer,
+    Basic,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum ManifestApi {
@@ -54,22 +101,24 @@ fn test_len(string: impl Into<String>, iden: &str, len: usize) -> String {
     string
 }
 
+/// A plugin's `ai-plugin.json`. Fields are declared in the order the spec
+/// documents them, which is the order they're serialized in.
 #[derive(Debug, Clone, Serialize, Deserialize, TypedBuilder)]
 pub struct Manifest {
     #[builder(setter(into))]
     pub schema_version: String,
 
-    #[builder(setter(transform = |n: impl Into<String>| test_len(n, "name_for_human", MAX_NAME_FOR_HUMAN)))]
-    pub name_for_human: String,
-
     #[builder(setter(transform = |n: impl Into<String>| test_len(n, "name_for_model", MAX_NAME_FOR_MODEL)))]
     pub name_for_model: String,
 
-    #[builder(setter(transform = |d: impl Into<String>| test_len(d, "description_for_human", MAX_DESCRIPTION_FOR_HUMAN)))]
-    pub description_for_human: String,
+    #[builder(setter(transform = |n: impl Into<String>| test_len(n, "name_for_human", MAX_NAME_FOR_HUMAN)))]
+    pub name_for_human: String,
 
     #[builder(setter(transform = |d: impl Into<String>| test_len(d, "description_for_model", MAX_DESCRIPTION_FOR_MODEL)))]
     pub description_for_model: String,
+
+    #[builder(setter(transform = |d: impl Into<String>| test_len(d, "description_for_human", MAX_DESCRIPTION_FOR_HUMAN)))]
+    pub description_for_human: String,
     pub auth: ManifestAuth,
     pub api: ManifestApi,
 
@@ -83,64 +132,962 @@ pub struct Manifest {
     pub legal_info_url: String,
 }
 
+const MANIFEST_SCHEMA_VERSION: &str = "v1";
+
+/// Whether `prefix` is `path` or one of its ancestors, segment by segment.
+fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path == prefix
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Lowercases `name`, splitting words at spaces, punctuation and lowercase to
+/// uppercase changes, and joins them with underscores.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len());
+    let mut previous: Option<char> = None;
+    for ch in name.chars() {
+        if ch.is_alphanumeric() {
+            let new_word = match previous {
+                Some(p) => !p.is_alphanumeric() || (p.is_lowercase() && ch.is_uppercase()),
+                None => false,
+            };
+            if new_word && !snake.is_empty() {
+                snake.push('_');
+            }
+            snake.extend(ch.to_lowercase());
+        }
+        previous = Some(ch);
+    }
+    snake
+}
+
+impl Manifest {
+    /// Builds a manifest for an unauthenticated OpenAPI plugin, using `name` and
+    /// `description` for both the human- and model-facing fields. The model-facing
+    /// name is `name` in snake case, e.g. `todo_list` for "Todo List".
+    ///
+    /// Panics if the name or description exceed the manifest's length limits.
+    pub fn minimal(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        api_url: impl Into<String>,
+        logo_url: impl Into<String>,
+        contact_email: impl Into<String>,
+        legal_info_url: impl Into<String>,
+    ) -> Self {
+        let name = name.into();
+        let description = description.into();
+
+        Self::builder()
+            .schema_version(MANIFEST_SCHEMA_VERSION)
+            .name_for_model(snake_case(&name))
+            .name_for_human(name)
+            .description_for_human(description.clone())
+            .description_for_model(description)
+            .auth(ManifestAuth::None)
+            .api(ManifestApi::Openapi {
+                url: api_url.into(),
+                is_user_authenticated: false,
+            })
+            .logo_url(logo_url)
+            .contact_email(contact_email)
+            .legal_info_url(legal_info_url)
+            .build()
+    }
+
+    /// Builds a manifest for an unauthenticated OpenAPI plugin served from
+    /// `base_url`, with the spec at `openapi.yaml`, the logo at `logo.png` and the
+    /// legal info at `legal` under it, and `support@<host>` as the contact email.
+    ///
+    /// Unlike the builder, this returns the problems [`validate`](Self::validate)
+    /// finds instead of panicking.
+    pub fn openapi_plugin(
+        name_for_human: impl Into<String>,
+        name_for_model: impl Into<String>,
+        description_for_human: impl Into<String>,
+        description_for_model: impl Into<String>,
+        base_url: &str,
+    ) -> Result<Self, Vec<ManifestError>> {
+        let invalid_base_url = || {
+            vec![ManifestError::InvalidUrl {
+                field: "base_url",
+                url: base_url.to_string(),
+            }]
+        };
+        let host = Url::parse(base_url)
+            .map_err(|_| invalid_base_url())?
+            .host_str()
+            .ok_or_else(invalid_base_url)?
+            .to_string();
+        let base_url = base_url.trim_end_matches('/');
+
+        let manifest = Self {
+            schema_version: MANIFEST_SCHEMA_VERSION.into(),
+            name_for_model: name_for_model.into(),
+            name_for_human: name_for_human.into(),
+            description_for_model: description_for_model.into(),
+            description_for_human: description_for_human.into(),
+            auth: ManifestAuth::None,
+            api: ManifestApi::Openapi {
+                url: format!("{base_url}/openapi.yaml"),
+                is_user_authenticated: false,
+            },
+            logo_url: format!("{base_url}/logo.png"),
+            contact_email: format!("support@{host}"),
+            legal_info_url: format!("{base_url}/legal"),
+        };
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// The `servers` entry the plugin's OpenAPI spec should declare: the origin
+    /// the manifest's API spec is served from.
+    pub fn api_server(&self) -> Server {
+        let ManifestApi::Openapi { url, .. } = &self.api;
+        let url = Url::parse(url).expect("error parsing API URL");
+        Server::new(url.origin().ascii_serialization())
+    }
+
+    /// Checks that the manifest agrees with the OpenAPI spec it points to, and
+    /// that `is_user_authenticated` agrees with `auth`.
+    ///
+    /// A spec's server matches if it shares the API URL's origin (or is relative)
+    /// and its path is a prefix of the API URL's, so `https://example.com/v1`
+    /// matches `https://example.com/v1/openapi.yaml` but `https://example.com/v2`
+    /// doesn't.
+    /// `is_user_authenticated` must be set for user HTTP and OAuth auth, and unset
+    /// otherwise.
+    pub fn check_consistency(&self, api: &OpenApi) -> Result<(), Vec<ManifestError>> {
+        let mut errors = Vec::new();
+        let ManifestApi::Openapi {
+            url,
+            is_user_authenticated,
+        } = &self.api;
+
+        match Url::parse(url) {
+            Ok(api_url) => {
+                let servers: Vec<_> = api
+                    .servers
+                    .iter()
+                    .flatten()
+                    .map(|s| s.url.clone())
+                    .collect();
+                let matches = servers.iter().any(|server| match Url::parse(server) {
+                    Ok(server) => {
+                        server.origin() == api_url.origin()
+                            && is_path_prefix(server.path(), api_url.path())
+                    }
+                    Err(_) => server.starts_with('/') && is_path_prefix(server, api_url.path()),
+                });
+                if !matches {
+                    errors.push(ManifestError::ServerMismatch {
+                        api_url: url.clone(),
+                        servers,
+                    });
+                }
+            }
+            Err(_) => errors.push(ManifestError::InvalidApiUrl(url.clone())),
+        }
+
+        let expected = matches!(self.auth, ManifestAuth::UserHttp | ManifestAuth::Oauth);
+        if *is_user_authenticated != expected {
+            errors.push(ManifestError::UserAuthenticationMismatch {
+                auth: self.auth.clone(),
+                is_user_authenticated: *is_user_authenticated,
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Checks the limits the builder enforces, for manifests that were deserialized
+    /// instead: field lengths, and that the URLs parse.
+    pub fn validate(&self) -> Result<(), Vec<ManifestError>> {
+        let mut errors = Vec::new();
+
+        for (field, value, max) in [
+            ("name_for_human", &self.name_for_human, MAX_NAME_FOR_HUMAN),
+            ("name_for_model", &self.name_for_model, MAX_NAME_FOR_MODEL),
+            (
+                "description_for_human",
+                &self.description_for_human,
+                MAX_DESCRIPTION_FOR_HUMAN,
+            ),
+            (
+                "description_for_model",
+                &self.description_for_model,
+                MAX_DESCRIPTION_FOR_MODEL,
+            ),
+        ] {
+            if value.len() > max {
+                errors.push(ManifestError::TooLong {
+                    field,
+                    max,
+                    len: value.len(),
+                });
+            }
+        }
+
+        let ManifestApi::Openapi { url, .. } = &self.api;
+        if Url::parse(url).is_err() {
+            errors.push(ManifestError::InvalidApiUrl(url.clone()));
+        }
+        for (field, url) in [
+            ("logo_url", &self.logo_url),
+            ("legal_info_url", &self.legal_info_url),
+        ] {
+            if Url::parse(url).is_err() {
+                errors.push(ManifestError::InvalidUrl {
+                    field,
+                    url: url.clone(),
+                });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Fetches and validates another plugin's manifest from its
+    /// `/.well-known/ai-plugin.json` URL.
+    ///
+    /// Fails with the first problem found; use [`validate`](Self::validate) on the
+    /// parsed manifest to list them all.
+    pub async fn fetch(
+        client: &reqwest::Client,
+        well_known_url: &str,
+    ) -> Result<Manifest, ManifestError> {
+        let fetch_error = |e: reqwest::Error| ManifestError::Fetch(e.to_string());
+
+        let response = client
+            .get(well_known_url)
+            .send()
+            .await
+            .map_err(fetch_error)?;
+        if !response.status().is_success() {
+            return Err(ManifestError::Fetch(format!(
+                "{well_known_url} returned {}",
+                response.status()
+            )));
+        }
+
+        let body = response.bytes().await.map_err(fetch_error)?;
+        let manifest: Manifest =
+            serde_json::from_slice(&body).map_err(|e| ManifestError::Parse(e.to_string()))?;
+        manifest
+            .validate()
+            .map_err(|mut errors| errors.swap_remove(0))?;
+        Ok(manifest)
+    }
+
+    /// Lists the fields that differ between `self` (e.g. the deployed manifest) and
+    /// `other`, in alphabetical order. `auth` and `api` are compared as a whole.
+    pub fn diff(&self, other: &Manifest) -> Vec<FieldChange> {
+        let old = serde_json::to_value(self).expect("error serializing manifest");
+        let new = serde_json::to_value(other).expect("error serializing manifest");
+        let (Some(old), Some(new)) = (old.as_object(), new.as_object()) else {
+            unreachable!("manifests serialize to objects");
+        };
+
+        old.iter()
+            .filter(|(field, value)| new.get(*field) != Some(*value))
+            .map(|(field, value)| FieldChange {
+                field: field.clone(),
+                old: value.clone(),
+                new: new[field].clone(),
+            })
+            .collect()
+    }
+}
+
+/// A manifest field that changed, as found by [`Manifest::diff`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: serde_json::Value,
+    pub new: serde_json::Value,
+}
+
+#[derive(Debug, Clone)]
+pub enum ManifestError {
+    InvalidApiUrl(String),
+    InvalidUrl {
+        field: &'static str,
+        url: String,
+    },
+    TooLong {
+        field: &'static str,
+        max: usize,
+        len: usize,
+    },
+    /// The manifest couldn't be downloaded.
+    Fetch(String),
+    /// The manifest wasn't valid JSON, or was missing fields.
+    Parse(String),
+    ServerMismatch {
+        api_url: String,
+        servers: Vec<String>,
+    },
+    UserAuthenticationMismatch {
+        auth: ManifestAuth,
+        is_user_authenticated: bool,
+    },
+}
+
+impl std::fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidApiUrl(url) => write!(f, "invalid API URL {url:?}"),
+            Self::InvalidUrl { field, url } => write!(f, "invalid {field} {url:?}"),
+            Self::TooLong { field, max, len } => {
+                write!(f, "{field} too long (expected <= {max}, got {len})")
+            }
+            Self::Fetch(e) => write!(f, "error fetching manifest: {e}"),
+            Self::Parse(e) => write!(f, "error parsing manifest: {e}"),
+            Self::ServerMismatch { api_url, servers } => write!(
+                f,
+                "API URL {api_url:?} doesn't match any of the spec's servers {servers:?}"
+            ),
+            Self::UserAuthenticationMismatch {
+                auth,
+                is_user_authenticated,
+            } => write!(
+                f,
+                "is_user_authenticated is {is_user_authenticated} but auth is {auth:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+#[derive(Debug, Clone, Default, TypedBuilder)]
+pub struct ServeOptions {
+    /// Serve the manifest as indented JSON rather than compact JSON.
+    #[builder(default)]
+    pub pretty_manifest: bool,
+
+    /// Additional specs to serve alongside the manifest's primary one, e.g. older API versions.
+    ///
+    /// Each is served at its first `servers` URL's path joined with the primary spec's
+    /// file name, so a server of `https://example.com/v1` next to a primary
+    /// `/openapi.yaml` is served at `/v1/openapi.yaml`. Specs without a server, or
+    /// whose route is already taken, are skipped with an error logged.
+    #[builder(default)]
+    pub extra_apis: Vec<OpenApi>,
+
+    /// Path the manifest's `/.well-known/ai-plugin.json` route is nested under, e.g.
+    /// `/plugins/todo`, so several plugins can share one domain.
+    #[builder(default, setter(into))]
+    pub well_known_prefix: String,
+
+    /// Require this auth's access token on the API spec routes, rejecting other
+    /// requests with `401 Unauthorized`, to keep the spec private to the plugin host.
+    #[builder(default, setter(strip_option))]
+    pub docs_auth: Option<ServiceAuth>,
+
+    /// Also require the `docs_auth` token on the manifest route.
+    #[builder(default)]
+    pub protect_manifest: bool,
+}
+
+const WELL_KNOWN_MANIFEST_PATH: &str = "/.well-known/ai-plugin.json";
+
+impl ServeOptions {
+    /// The route the manifest is served at.
+    pub fn manifest_route(&self) -> String {
+        let prefix = self.well_known_prefix.trim_end_matches('/');
+        if prefix.is_empty() || prefix.starts_with('/') {
+            format!("{prefix}{WELL_KNOWN_MANIFEST_PATH}")
+        } else {
+            format!("/{prefix}{WELL_KNOWN_MANIFEST_PATH}")
+        }
+    }
+}
+
 struct ServeState {
     manifest: Manifest,
     openapi: OpenApi,
+    extra_apis: HashMap<String, OpenApi>,
     logo: Vec<u8>,
+    options: ServeOptions,
+}
+
+/// The route an extra API is served at, or `None` if it declares no server.
+fn extra_api_route(api: &OpenApi, file_name: &str) -> Option<String> {
+    let server = api.servers.as_ref()?.first()?;
+
+    let path = match Url::parse(&server.url) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => server.url.clone(),
+    };
+
+    Some(format!("{}/{}", path.trim_end_matches('/'), file_name))
 }
 
-pub fn serve_plugin_info<B>(manifest: Manifest, api: OpenApi, icon_path: &str) -> Router<(), B>
+pub fn serve_plugin_info<S>(manifest: Manifest, api: OpenApi, icon_path: &str) -> Router<S>
 where
-    B: HttpBody + Send + 'static,
+    S: Clone + Send + Sync + 'static,
 {
-    let ManifestApi::Openapi { url, .. } = &manifest.api;
-    let url = Url::parse(url).expect("error parsing API URL");
-    let api_route = url.path();
+    serve_plugin_info_with_options(manifest, api, icon_path, ServeOptions::default())
+}
 
-    let url = Url::parse(&manifest.logo_url).expect("error parsing icon URL");
-    let icon_route = url.path();
+pub fn serve_plugin_info_with_options<S>(
+    manifest: Manifest,
+    api: OpenApi,
+    icon_path: &str,
+    options: ServeOptions,
+) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    PluginInfo::builder()
+        .manifest(manifest)
+        .openapi(api)
+        .logo(std::fs::read(icon_path).expect("error reading logo file"))
+        .options(options)
+        .build()
+        .router()
+}
 
-    let state = Arc::new(ServeState {
-        manifest,
-        openapi: api,
-        logo: std::fs::read(icon_path).expect("error reading logo file"),
-    });
+/// Everything [`serve_plugin_info`] serves, built in memory rather than read from
+/// disk, e.g. to serve test fixtures or a logo embedded with `include_bytes!`.
+#[derive(Debug, Clone, TypedBuilder)]
+pub struct PluginInfo {
+    pub manifest: Manifest,
+    pub openapi: OpenApi,
 
-    Router::new()
-        .route("/.well-known/ai-plugin.json", get(serve_manifest))
-        .route(api_route, get(serve_api_docs))
-        .route(icon_route, get(serve_icon))
-        .with_state(state)
+    /// The PNG served at the manifest's `logo_url`.
+    #[builder(setter(into))]
+    pub logo: Vec<u8>,
+
+    #[builder(default)]
+    pub options: ServeOptions,
 }
 
-async fn serve_manifest(State(state): State<Arc<ServeState>>) -> Json<Manifest> {
-    Json::from(state.manifest.clone())
+impl PluginInfo {
+    /// Routes the manifest, API specs and logo.
+    pub fn router<S>(self) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let Self {
+            manifest,
+            openapi,
+            logo,
+            mut options,
+        } = self;
+
+        let ManifestApi::Openapi { url, .. } = &manifest.api;
+        let url = Url::parse(url).expect("error parsing API URL");
+        let api_route = url.path();
+        let api_file_name = api_route.rsplit('/').next().unwrap_or_default();
+
+        let mut extra_apis = HashMap::new();
+        for api in std::mem::take(&mut options.extra_apis) {
+            let Some(route) = extra_api_route(&api, api_file_name) else {
+                log::error!(
+                    "skipping extra API {:?}, which declares no server",
+                    api.info.title
+                );
+                continue;
+            };
+            if route == api_route || extra_apis.contains_key(&route) {
+                log::error!(
+                    "skipping extra API {:?}, another spec is already served at {route}",
+                    api.info.title
+                );
+                continue;
+            }
+            extra_apis.insert(route, api);
+        }
+        let extra_routes: Vec<_> = extra_apis.keys().cloned().collect();
+
+        let url = Url::parse(&manifest.logo_url).expect("error parsing icon URL");
+        let icon_route = url.path();
+        let manifest_route = options.manifest_route();
+
+        let state = Arc::new(ServeState {
+            manifest,
+            openapi,
+            extra_apis,
+            logo,
+            options,
+        });
+
+        let docs_auth = state.options.docs_auth.clone();
+        let protect = |router: Router<_>| match &docs_auth {
+            Some(auth) => auth.protect(router),
+            None => router,
+        };
+
+        let mut docs = Router::new().route(api_route, get(serve_api_docs));
+        for route in extra_routes {
+            docs = docs.route(&route, get(serve_extra_api_docs));
+        }
+        let mut manifest = Router::new().route(&manifest_route, get(serve_manifest));
+        if state.options.protect_manifest {
+            manifest = protect(manifest);
+        }
+
+        let router = Router::new()
+            .route(icon_route, get(serve_icon))
+            .merge(protect(docs))
+            .merge(manifest);
+
+        router.with_state(state)
+    }
+}
+
+async fn serve_manifest(
+    State(state): State<Arc<ServeState>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let body = if state.options.pretty_manifest {
+        serde_json::to_vec_pretty(&state.manifest)
+    } else {
+        serde_json::to_vec(&state.manifest)
+    }
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Response::builder()
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(body))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
 async fn serve_api_docs(
     State(state): State<Arc<ServeState>>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    Ok(Response::builder()
+    api_docs_response(&state.openapi)
+}
+
+async fn serve_extra_api_docs(
+    State(state): State<Arc<ServeState>>,
+    path: MatchedPath,
+) -> Result<impl IntoResponse, StatusCode> {
+    let api = state
+        .extra_apis
+        .get(path.as_str())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    api_docs_response(api)
+}
+
+fn api_docs_response(api: &OpenApi) -> Result<Response, StatusCode> {
+    Response::builder()
         .header("Content-Type", "application/yaml")
-        .body(Full::from(
-            state
-                .openapi
-                .to_yaml()
+        .body(Body::from(
+            api.to_yaml()
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
         ))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
 async fn serve_icon(State(state): State<Arc<ServeState>>) -> Result<impl IntoResponse, StatusCode> {
-    Ok(Response::builder()
+    Response::builder()
         .header("Content-Type", "image/png")
-        .body(Full::from(state.logo.clone()))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?)
+        .body(Body::from(state.logo.clone()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use utoipa::openapi::OpenApiBuilder;
+
+    fn todo_manifest() -> Manifest {
+        Manifest::minimal(
+            "Todo",
+            "Manages a TODO list.",
+            "http://localhost:3030/openapi.yaml",
+            "http://localhost:3030/logo.png",
+            "support@example.com",
+            "http://example.com/legal",
+        )
+    }
+
+    #[test]
+    fn test_check_consistency() {
+        let manifest = todo_manifest();
+
+        let api = OpenApiBuilder::new()
+            .servers(Some([manifest.api_server()]))
+            .build();
+        assert!(manifest.check_consistency(&api).is_ok());
+
+        let versioned = Manifest::minimal(
+            "Todo",
+            "Manages a TODO list.",
+            "http://localhost:3030/v1/openapi.yaml",
+            "http://localhost:3030/logo.png",
+            "support@example.com",
+            "http://example.com/legal",
+        );
+        let consistent = |server: &str| {
+            let api = OpenApiBuilder::new()
+                .servers(Some([Server::new(server)]))
+                .build();
+            versioned.check_consistency(&api).is_ok()
+        };
+        assert!(consistent("http://localhost:3030/v1"));
+        assert!(consistent("http://localhost:3030/v1/"));
+        assert!(consistent("/v1"));
+        assert!(!consistent("http://localhost:3030/v2"));
+        assert!(!consistent("http://localhost:3030/v"));
+        assert!(!consistent("/v2"));
+
+        let mut manifest = manifest;
+        manifest.auth = ManifestAuth::Oauth;
+        let api = OpenApiBuilder::new()
+            .servers(Some([Server::new("https://example.com")]))
+            .build();
+        let errors = manifest.check_consistency(&api).unwrap_err();
+        assert!(matches!(errors[0], ManifestError::ServerMismatch { .. }));
+        assert!(matches!(
+            errors[1],
+            ManifestError::UserAuthenticationMismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn test_minimal() {
+        let manifest = Manifest::minimal(
+            "Todo List",
+            "Manages a TODO list.",
+            "http://localhost:3030/openapi.yaml",
+            "http://localhost:3030/logo.png",
+            "support@example.com",
+            "http://example.com/legal",
+        );
+        assert_eq!(manifest.name_for_human, "Todo List");
+        assert_eq!(manifest.name_for_model, "todo_list");
+        assert_eq!(manifest.description_for_model, "Manages a TODO list.");
+        assert!(manifest.validate().is_ok());
+
+        assert_eq!(snake_case("TodoList 2.0!"), "todo_list_2_0");
+        assert_eq!(snake_case("  Todo--PLUGIN "), "todo_plugin");
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut manifest = todo_manifest();
+        assert!(manifest.validate().is_ok());
+
+        manifest.name_for_human = "To-Do Plugin Name that is Way TOO LONG!!!".into();
+        manifest.logo_url = "logo.png".into();
+        let errors = manifest.validate().unwrap_err();
+        assert!(matches!(
+            errors[0],
+            ManifestError::TooLong {
+                field: "name_for_human",
+                ..
+            }
+        ));
+        assert!(matches!(
+            errors[1],
+            ManifestError::InvalidUrl {
+                field: "logo_url",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_openapi_plugin() {
+        let manifest = Manifest::openapi_plugin(
+            "Todo",
+            "todo",
+            "Manages a TODO list.",
+            "Plugin for managing a TODO list.",
+            "https://example.com/plugins/todo/",
+        )
+        .unwrap();
+        assert_eq!(manifest.schema_version, "v1");
+        assert!(matches!(manifest.auth, ManifestAuth::None));
+        let ManifestApi::Openapi { url, .. } = &manifest.api;
+        assert_eq!(url, "https://example.com/plugins/todo/openapi.yaml");
+        assert_eq!(
+            manifest.logo_url,
+            "https://example.com/plugins/todo/logo.png"
+        );
+        assert_eq!(manifest.contact_email, "support@example.com");
+
+        let errors = Manifest::openapi_plugin(
+            "To-Do Plugin Name that is Way TOO LONG!!!",
+            "todo",
+            "Manages a TODO list.",
+            "Plugin for managing a TODO list.",
+            "http://localhost:3030",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [ManifestError::TooLong {
+                field: "name_for_human",
+                ..
+            }]
+        ));
+
+        let errors = Manifest::openapi_plugin("Todo", "todo", "", "", "localhost").unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [ManifestError::InvalidUrl {
+                field: "base_url",
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_field_order() {
+        let manifest = todo_manifest();
+        let json = serde_json::to_string(&manifest).unwrap();
+
+        let fields = [
+            "schema_version",
+            "name_for_model",
+            "name_for_human",
+            "description_for_model",
+            "description_for_human",
+            "auth",
+            "api",
+            "logo_url",
+            "contact_email",
+            "legal_info_url",
+        ];
+        let positions: Vec<_> = fields
+            .iter()
+            .map(|field| json.find(&format!("\"{field}\":")).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_manifest_route() {
+        let route = |prefix: &str| {
+            ServeOptions::builder()
+                .well_known_prefix(prefix)
+                .build()
+                .manifest_route()
+        };
+
+        assert_eq!(route(""), "/.well-known/ai-plugin.json");
+        assert_eq!(
+            route("/plugins/todo/"),
+            "/plugins/todo/.well-known/ai-plugin.json"
+        );
+        assert_eq!(route("todo"), "/todo/.well-known/ai-plugin.json");
+    }
+
+    #[tokio::test]
+    async fn test_docs_auth() {
+        use axum::http::{header::AUTHORIZATION, Request};
+        use tower::ServiceExt;
+
+        let router: Router = PluginInfo::builder()
+            .manifest(todo_manifest())
+            .openapi(OpenApiBuilder::new().build())
+            .logo(b"png".as_slice())
+            .options(
+                ServeOptions::builder()
+                    .docs_auth(ServiceAuth::new("verify-me", "secret"))
+                    .build(),
+            )
+            .build()
+            .router();
+
+        let status = |uri: &'static str, token: Option<&'static str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(token) = token {
+                request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let router = router.clone();
+            async move { router.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(
+            status("/openapi.yaml", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status("/openapi.yaml", Some("secret")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status("/.well-known/ai-plugin.json", None).await,
+            StatusCode::OK
+        );
+        assert_eq!(status("/logo.png", None).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_plugin_info() {
+        use axum::http::Request;
+        use tower::ServiceExt;
+
+        let manifest = Manifest::minimal(
+            "Todo",
+            "Manages a TODO list.",
+            "http://localhost:3030/openapi.yaml",
+            "http://localhost:3030/images/logo.png",
+            "support@example.com",
+            "http://example.com/legal",
+        );
+        let router: Router = PluginInfo::builder()
+            .manifest(manifest)
+            .openapi(OpenApiBuilder::new().build())
+            .logo(b"png".as_slice())
+            .build()
+            .router();
+
+        let request = Request::builder()
+            .uri("/images/logo.png")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"png");
+    }
+
+    #[tokio::test]
+    async fn test_serve_manifest() {
+        use axum::http::{header::CONTENT_TYPE, Request};
+        use tower::ServiceExt;
+
+        let manifest = todo_manifest();
+        let serve = |pretty_manifest: bool| {
+            let router: Router = PluginInfo::builder()
+                .manifest(manifest.clone())
+                .openapi(OpenApiBuilder::new().build())
+                .logo(b"png".as_slice())
+                .options(
+                    ServeOptions::builder()
+                        .pretty_manifest(pretty_manifest)
+                        .build(),
+                )
+                .build()
+                .router();
+            let request = Request::builder()
+                .uri("/.well-known/ai-plugin.json")
+                .body(Body::empty())
+                .unwrap();
+            async move {
+                let response = router.oneshot(request).await.unwrap();
+                assert_eq!(
+                    response.headers()[CONTENT_TYPE],
+                    "application/json; charset=utf-8"
+                );
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        assert_eq!(
+            serve(false).await,
+            serde_json::to_string(&manifest).unwrap()
+        );
+        assert_eq!(
+            serve(true).await,
+            serde_json::to_string_pretty(&manifest).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extra_apis() {
+        use axum::http::Request;
+        use tower::ServiceExt;
+        use utoipa::openapi::InfoBuilder;
+
+        let api = |title: &str, server: Option<&str>| {
+            OpenApiBuilder::new()
+                .info(InfoBuilder::new().title(title).build())
+                .servers(server.map(|url| [Server::new(url)]))
+                .build()
+        };
+
+        let manifest = todo_manifest();
+        let router: Router = PluginInfo::builder()
+            .manifest(manifest)
+            .openapi(api("current", None))
+            .logo(b"png".as_slice())
+            .options(
+                ServeOptions::builder()
+                    .extra_apis(vec![
+                        api("v1", Some("https://example.com/v1")),
+                        api("no server", None),
+                        api("v1 again", Some("/v1/")),
+                        api("root", Some("https://example.com")),
+                    ])
+                    .build(),
+            )
+            .build()
+            .router();
+
+        let served = |uri: &'static str| {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let router = router.clone();
+            async move {
+                let response = router.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        // The root spec's route is the primary one's, and the second /v1 spec's is
+        // taken by the first, so both are skipped.
+        assert_eq!(
+            served("/openapi.yaml").await,
+            api("current", None).to_yaml().unwrap()
+        );
+        assert_eq!(
+            served("/v1/openapi.yaml").await,
+            api("v1", Some("https://example.com/v1")).to_yaml().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let deployed = todo_manifest();
+        assert!(deployed.diff(&deployed).is_empty());
+
+        let mut updated = deployed.clone();
+        updated.name_for_human = "Todo List".into();
+        updated.auth = ManifestAuth::UserHttp;
+
+        assert_eq!(
+            deployed.diff(&updated),
+            vec![
+                FieldChange {
+                    field: "auth".into(),
+                    old: serde_json::json!({ "type": "none" }),
+                    new: serde_json::json!({ "type": "user_http" }),
+                },
+                FieldChange {
+                    field: "name_for_human".into(),
+                    old: "Todo".into(),
+                    new: "Todo List".into(),
+                },
+            ]
+        );
+    }
 
     #[test]
     #[should_panic]
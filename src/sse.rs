@@ -0,0 +1,110 @@
+use axum::response::sse::{Event, Sse};
+use futures::stream::{Stream, StreamExt};
+use reqwest::Client;
+use std::convert::Infallible;
+
+use crate::api::backend::{ChatBackend, ChunkStream};
+use crate::api::chat::{ChatDelta, ChatRequest};
+
+/// Proxies a streamed chat completion as a server-sent event response.
+///
+/// Each content delta is forwarded as a `data` event holding the delta as a JSON
+/// string, so carriage returns in the content are escaped and a delta can't be
+/// mistaken for the `data: [DONE]` the stream always ends with. Failures,
+/// including a request that doesn't have `stream` set, are sent as an `error`
+/// event carrying the message, also as a JSON string.
+pub fn chat_sse_response(
+    request: ChatRequest,
+    client: &Client,
+    api_key: &str,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    sse_response(request.stream(client, api_key).map(StreamExt::boxed))
+}
+
+/// Like [`chat_sse_response`], but streams from `backend`.
+pub fn chat_sse_response_with(
+    request: ChatRequest,
+    backend: &dyn ChatBackend,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    sse_response(request.stream_with(backend))
+}
+
+fn sse_response(
+    chunks: Result<ChunkStream, Box<dyn std::error::Error + Send + Sync>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = match chunks {
+        Ok(chunks) => chunks
+            .filter_map(|chunk| async move {
+                match chunk {
+                    Ok(chunk) => match chunk.delta() {
+                        Some(ChatDelta::Content(content)) => {
+                            Some(Event::default().data(json_string(&content)))
+                        }
+                        _ => None,
+                    },
+                    Err(e) => Some(error_event(e)),
+                }
+            })
+            .left_stream(),
+        Err(e) => futures::stream::once(async move { error_event(e) }).right_stream(),
+    };
+
+    Sse::new(
+        events
+            .chain(futures::stream::once(async {
+                Event::default().data("[DONE]")
+            }))
+            .map(Ok),
+    )
+}
+
+fn error_event(error: Box<dyn std::error::Error + Send + Sync>) -> Event {
+    Event::default()
+        .event("error")
+        .data(json_string(&error.to_string()))
+}
+
+/// Encodes `text` as a JSON string, which never contains a raw newline or
+/// carriage return, either of which would break the event apart.
+fn json_string(text: &str) -> String {
+    serde_json::to_string(text).expect("error serializing string")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::backend::MockBackend;
+    use axum::response::IntoResponse;
+
+    async fn body_text(
+        sse: Sse<impl Stream<Item = Result<Event, Infallible>> + Send + 'static>,
+    ) -> String {
+        let body = sse.into_response().into_body();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_error_event() {
+        let request = ChatRequest::builder().user("Hi").build();
+        let text = body_text(chat_sse_response(request, &Client::new(), "key")).await;
+
+        assert_eq!(
+            text,
+            "event: error\ndata: \"\\\"stream\\\" must be set to true\"\n\ndata: [DONE]\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_content_events() {
+        let backend = MockBackend::new();
+        backend.push_content_stream(["line one\r\n", "[DONE]"]);
+        let request = ChatRequest::builder().user("Hi").stream(true).build();
+        let text = body_text(chat_sse_response_with(request, &backend)).await;
+
+        assert_eq!(
+            text,
+            "data: \"line one\\r\\n\"\n\ndata: \"[DONE]\"\n\ndata: [DONE]\n\n"
+        );
+    }
+}